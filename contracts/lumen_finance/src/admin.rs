@@ -1,18 +1,33 @@
 use soroban_sdk::{Address, Env};
 
-use crate::AdminDataKey;
+use crate::{AdminDataKey, Error};
 
-pub fn has_administrator(e: &Env) -> bool {
+pub fn read_administrator(e: &Env) -> Result<Address, Error> {
     let key = AdminDataKey::ADMIN;
-    e.storage().instance().has(&key)
+    e.storage().instance().get(&key).ok_or(Error::AdminRenounced)
 }
 
-pub fn read_administrator(e: &Env) -> Address {
+pub fn write_administrator(e: &Env, id: &Address) {
     let key = AdminDataKey::ADMIN;
-    e.storage().instance().get(&key).unwrap()
+    e.storage().instance().set(&key, id);
 }
 
-pub fn write_administrator(e: &Env, id: &Address) {
+pub fn remove_administrator(e: &Env) {
     let key = AdminDataKey::ADMIN;
+    e.storage().instance().remove(&key);
+}
+
+pub fn read_pending_administrator(e: &Env) -> Option<Address> {
+    let key = AdminDataKey::PENDINGADMIN;
+    e.storage().instance().get(&key)
+}
+
+pub fn write_pending_administrator(e: &Env, id: &Address) {
+    let key = AdminDataKey::PENDINGADMIN;
     e.storage().instance().set(&key, id);
 }
+
+pub fn remove_pending_administrator(e: &Env) {
+    let key = AdminDataKey::PENDINGADMIN;
+    e.storage().instance().remove(&key);
+}
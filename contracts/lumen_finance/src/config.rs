@@ -0,0 +1,65 @@
+use soroban_sdk::{contracttype, Env};
+
+use crate::{
+    DataKey, Error, BPS_DENOMINATOR, DEFAULT_GRACE_PERIOD, DEFAULT_PENALTY_APR_BPS, INSURANCE_FEE_BPS, MAX_APR_BPS,
+};
+
+// Protocol parameters, changed through the timelock
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolConfig {
+    // Cuts of every fee and penalty paid, LPs keep the rest. Fixed on a loan
+    // when it is approved.
+    pub insurance_fee_bps: i128,
+    pub protocol_fee_bps: i128,
+    // Highest APR a loan can be priced at
+    pub max_apr_bps: i128,
+    // Time from the loan request to the repayment date
+    pub min_tenor: u64,
+    pub max_tenor: u64,
+    // Largest advance as a share of the invoice
+    pub max_advance_bps: i128,
+    // Late payment terms fixed on a loan when it is approved
+    pub grace_period: u64,
+    pub penalty_apr_bps: i128,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            insurance_fee_bps: INSURANCE_FEE_BPS,
+            protocol_fee_bps: 0,
+            max_apr_bps: MAX_APR_BPS,
+            min_tenor: 0,
+            max_tenor: u64::MAX,
+            max_advance_bps: BPS_DENOMINATOR,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            penalty_apr_bps: DEFAULT_PENALTY_APR_BPS,
+        }
+    }
+}
+
+impl PoolConfig {
+    pub fn validate(&self) -> Result<(), Error> {
+        let fee_cuts_valid = self.insurance_fee_bps >= 0
+            && self.protocol_fee_bps >= 0
+            && self.insurance_fee_bps + self.protocol_fee_bps <= BPS_DENOMINATOR;
+        if !fee_cuts_valid
+            || !(0..=MAX_APR_BPS).contains(&self.max_apr_bps)
+            || self.min_tenor > self.max_tenor
+            || !(1..=BPS_DENOMINATOR).contains(&self.max_advance_bps)
+            || !(0..=self.max_apr_bps).contains(&self.penalty_apr_bps)
+        {
+            return Err(Error::InvalidConfig);
+        }
+        Ok(())
+    }
+}
+
+pub fn read_config(e: &Env) -> PoolConfig {
+    e.storage().instance().get(&DataKey::Config).unwrap_or_default()
+}
+
+pub fn write_config(e: &Env, config: &PoolConfig) {
+    e.storage().instance().set(&DataKey::Config, config);
+}
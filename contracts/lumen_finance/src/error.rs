@@ -0,0 +1,51 @@
+use soroban_sdk::contracterror;
+
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Error {
+    NotInitialized = 1,
+    AlreadyInitialized = 2,
+    InvalidAmount = 3,
+    InsufficientShares = 4,
+    InsufficientLiquidity = 5,
+    NotWhitelisted = 6,
+    LoanNotFound = 7,
+    AlreadyApproved = 8,
+    FeeRateTooHigh = 9,
+    NotApproved = 10,
+    AlreadyReleased = 11,
+    NotReleased = 12,
+    RepaymentDateNotReached = 13,
+    AlreadyRepaid = 14,
+    InvalidStatusTransition = 15,
+    NotOverdue = 16,
+    DuplicateInvoice = 17,
+    InvalidRepaymentDate = 18,
+    InvalidSchedule = 19,
+    DefaultWindowNotElapsed = 20,
+    InsufficientJuniorCoverage = 21,
+    WithdrawalNotFound = 22,
+    WithdrawalNotPending = 23,
+    WithdrawalNotFunded = 24,
+    WithdrawalQueueNotEmpty = 25,
+    LockupNotExpired = 26,
+    SlippageExceeded = 27,
+    DeadlinePassed = 28,
+    NoPendingAdmin = 29,
+    AdminRenounced = 30,
+    MissingRole = 31,
+    Paused = 32,
+    MigrationRequired = 33,
+    AlreadyMigrated = 34,
+    ChangeNotFound = 35,
+    ChangeNotQueued = 36,
+    TimelockNotElapsed = 37,
+    TermsNotProposed = 38,
+    AlreadyEndorsed = 39,
+    InvalidConfig = 40,
+    TenorOutOfRange = 41,
+    AdvanceRateTooHigh = 42,
+    InvalidAdvance = 43,
+    SchemaNotSupported = 44,
+}
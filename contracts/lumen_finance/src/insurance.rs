@@ -0,0 +1,11 @@
+use soroban_sdk::{contractclient, Env};
+
+// Interface of the companion insurance reserve contract (contracts/lumen_insurance)
+#[allow(dead_code)]
+#[contractclient(name = "Client")]
+pub trait Insurance {
+    // Credit a premium the pool has already transferred to the reserve
+    fn receive_premium(e: Env, loan_id: u64, amount: i128);
+    // Pay the covered share of a written-off loan's loss to the pool
+    fn claim(e: Env, loan_id: u64, loss: i128) -> i128;
+}
@@ -2,17 +2,99 @@
 
 mod test;
 mod token;
+mod insurance;
 mod admin;
+mod config;
+mod error;
+mod loan;
+mod lockup;
+mod migration;
+mod pause;
+mod role;
+mod timelock;
+mod tranche;
+mod withdrawal;
 
-use num_integer::Roots;
 use soroban_sdk::{
-    contract, contractimpl, contracttype, symbol_short, Address, BytesN, ConversionError, Env, IntoVal,
+    contract, contractimpl, contracttype, vec, Address, BytesN, ConversionError, Env, IntoVal, Map,
     TryFromVal, Val, Vec, Symbol
 };
 use token::create_contract;
-use admin::{has_administrator, read_administrator, write_administrator};
+use config::{read_config, write_config};
+use admin::{
+    read_administrator, read_pending_administrator, remove_administrator, remove_pending_administrator,
+    write_administrator, write_pending_administrator,
+};
+pub use config::PoolConfig;
+pub use error::Error;
+pub use loan::{Installment, LoanDetails, LoanStatus};
+pub use lockup::Lockup;
+pub use migration::SCHEMA_VERSION;
+pub use pause::Operation;
+pub use role::Role;
+pub use timelock::{ChangeStatus, ParamChange, QueuedChange};
+pub use tranche::Tranche;
+pub use withdrawal::{WithdrawRequest, WithdrawStatus};
+use loan::{
+    bump_loan, check_schedule, discount, read_loan, read_loan_id, register_loan_id, write_loan,
+};
+use lockup::{add_lockup, read_lockups, total_locked, write_lockups};
+use migration::{get_schema_version, migrate_loans, migrate_settings, put_schema_version};
+use pause::{
+    check_not_paused, is_globally_paused, is_operation_paused, put_globally_paused, put_operation_paused,
+};
+use role::{check_role, has_role, remove_role, write_role};
+use timelock::{get_timelock_delay, next_change_id, put_timelock_delay, read_change, write_change};
+use tranche::{checkpoint_senior_index, credit_senior_index, senior_index_now, SENIOR_INDEX_SCALE};
+use withdrawal::{
+    get_parked_head, get_queue_head, next_parked_slot, next_withdraw_id, park_request, put_parked_head,
+    put_queue_head, read_parked_request, read_withdraw_request, register_withdraw_id, remove_parked_request,
+    write_withdraw_request,
+};
 
-pub(crate) const FEES_PORTION_FOR_INSURANCE: i128 = 10;
+// All rates are in basis points. Wherever a rate splits or charges an amount
+// the result is rounded in favour of the pool: discounts round up, the
+// insurance cut of a fee rounds down.
+pub(crate) const BPS_DENOMINATOR: i128 = 10_000;
+// Portion of loan fees paid to the insurance reserve until configured, 10%
+pub(crate) const INSURANCE_FEE_BPS: i128 = 1_000;
+// Highest APR underwriting can ever price a loan at, 100%
+pub(crate) const MAX_APR_BPS: i128 = 10_000;
+pub(crate) const SECONDS_PER_DAY: u64 = 86_400;
+pub(crate) const DAYS_PER_YEAR: i128 = 365;
+pub(crate) const SECONDS_PER_YEAR: i128 = DAYS_PER_YEAR * SECONDS_PER_DAY as i128;
+// Late payment terms applied to newly approved loans until the admin changes them
+pub(crate) const DEFAULT_GRACE_PERIOD: u64 = 3 * SECONDS_PER_DAY;
+pub(crate) const DEFAULT_PENALTY_APR_BPS: i128 = 0;
+// How long an installment can stay unpaid before the loan can be defaulted
+pub(crate) const DEFAULT_DEFAULT_WINDOW: u64 = 30 * SECONDS_PER_DAY;
+// Tranche terms until the admin changes them: senior capped at 8% APR, junior
+// at least 20% of NAV whenever there are seniors to cover
+pub(crate) const DEFAULT_SENIOR_APR_BPS: i128 = 800;
+pub(crate) const DEFAULT_MIN_JUNIOR_COVERAGE_BPS: i128 = 2_000;
+// A single underwriter approves any invoice until a quorum is configured
+pub(crate) const DEFAULT_ENDORSEMENT_QUORUM: u32 = 1;
+// Queued parameter changes wait two days, giving LPs time to exit
+pub(crate) const DEFAULT_TIMELOCK_DELAY: u64 = 2 * SECONDS_PER_DAY;
+// Withdrawal queue entries read per call, funded or not, so cancelled requests
+// piling up in the queue cannot push repayments over the resource budget
+pub(crate) const MAX_QUEUE_ENTRIES_PER_CALL: u64 = 10;
+// Fixed-point scale for the per-second fee accrual rate
+pub(crate) const ACCRUAL_SCALE: i128 = 1_000_000_000;
+// Shares priced into every tranche on top of the real ones, see
+// `share_price_basis`
+pub(crate) const VIRTUAL_SHARES: i128 = 1_000;
+// Share price is quoted per whole GFS (7 decimals)
+pub(crate) const SHARE_PRICE_SCALE: i128 = 10_000_000;
+
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+// Global config and totals, bumped on every invocation
+pub(crate) const INSTANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+pub(crate) const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+// Loans and whitelist entries, bumped whenever they are touched. Long enough
+// to outlive the longest invoice tenor between two touches.
+pub(crate) const LOAN_BUMP_AMOUNT: u32 = 120 * DAY_IN_LEDGERS;
+pub(crate) const LOAN_LIFETIME_THRESHOLD: u32 = LOAN_BUMP_AMOUNT - 7 * DAY_IN_LEDGERS;
 
 #[derive(Clone, Copy)]
 #[repr(u32)]
@@ -24,27 +106,81 @@ pub enum DataKey {
     TotalLoanAmount = 4,
     TotalOutstandingLoan = 5,
     FeesEarned = 6,
+    OutstandingFees = 7,
+    AccruedFees = 8,
+    FeeAccrualRate = 9,
+    LastAccrual = 10,
+    Impairments = 11,
+    NextLoanId = 12,
+    // Moved into `PoolConfig` in schema 3, only read by the migration
+    GracePeriod = 13,
+    PenaltyRate = 14,
+    // Time an installment can stay unpaid before the loan can be defaulted
+    DefaultWindow = 15,
+    // TokenShare and TotalShares belong to the junior tranche
+    SeniorTokenShare = 16,
+    SeniorTotalShares = 17,
+    SeniorIndex = 18,
+    SeniorIndexAt = 19,
+    SeniorRate = 20,
+    MinJuniorCoverage = 21,
+    NextWithdrawId = 22,
+    WithdrawQueueHead = 23,
+    // USDC set aside for funded withdrawals not yet claimed
+    WithdrawReserve = 24,
+    SeniorQueuedShares = 25,
+    JuniorQueuedShares = 26,
+    LockupPeriod = 27,
+    EarlyExitFee = 28,
+    IdleAssets = 29,
+    Paused = 30,
+    SchemaVersion = 31,
+    // Next loan to convert while a migration is in progress
+    MigrationCursor = 32,
+    TimelockDelay = 33,
+    NextChangeId = 34,
+    EndorsementThreshold = 35,
+    EndorsementQuorum = 36,
+    Config = 37,
+    // Protocol cut of fees held until the treasury collects it
+    ProtocolFees = 38,
+    // Accrual rate that stops at each repayment date of the released loans
+    FeeMaturities = 39,
+    // Queue of junior withdrawals parked behind the minimum coverage
+    ParkedHead = 40,
+    NextParkedSlot = 41,
+    ParkedJuniorShares = 42,
 }
 
+// Tracked pool assets against the USDC the contract actually holds
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub enum AdminDataKey {
-    ADMIN,
-    WHITELISTED(Address),
-    INVNO(u32),
+pub struct BalanceCheck {
+    pub idle_assets: i128,
+    pub withdraw_reserve: i128,
+    pub protocol_fees: i128,
+    pub balance: i128,
+    // Untracked USDC that can be skimmed, negative if the invariant is broken
+    pub surplus: i128,
 }
 
 #[contracttype]
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct LoanDetails {
-    pub who: Address,
-    pub fee_rate: i128,
-    pub invoice_amount: i128,
-    pub loan_amount: i128,
-    pub repayment_date: u64,
-    pub approved: bool,
-    pub released: bool,
-    pub repaid: bool,
+pub enum AdminDataKey {
+    ADMIN,
+    PENDINGADMIN,
+    WHITELISTED(Address),
+    LOAN(u64),
+    INVREF(Address, u32),
+    WITHDRAWAL(u64),
+    // Shares a holder deposited into a tranche that are still locked up
+    LOCKED(Address, Tranche),
+    ROLE(Role, Address),
+    // Per-operation circuit breakers, kept in instance storage
+    PAUSED(Operation),
+    CHANGE(u64),
+    // Request id parked in a slot of the junior parked queue
+    PARKED(u64),
 }
 
 impl TryFromVal<Env, DataKey> for Val {
@@ -59,20 +195,16 @@ fn get_usdc(e: &Env) -> Address {
     e.storage().instance().get(&DataKey::Usdc).unwrap()
 }
 
-fn get_token_share(e: &Env) -> Address {
-    e.storage().instance().get(&DataKey::TokenShare).unwrap()
+fn get_token_share(e: &Env, tranche: Tranche) -> Address {
+    e.storage().instance().get(&tranche.token_share_key()).unwrap()
 }
 
 fn get_insurance_address(e: &Env) -> Address {
     e.storage().instance().get(&DataKey::InsuranceAddress).unwrap()
 }
 
-fn get_total_shares(e: &Env) -> i128 {
-    e.storage().instance().get(&DataKey::TotalShares).unwrap()
-}
-
-fn get_balance(e: &Env, contract: Address) -> i128 {
-    token::Client::new(e, &contract).balance(&e.current_contract_address())
+fn get_total_shares(e: &Env, tranche: Tranche) -> i128 {
+    e.storage().instance().get(&tranche.total_shares_key()).unwrap()
 }
 
 fn get_total_loan_amount(e: &Env) -> i128 {
@@ -87,28 +219,104 @@ fn get_fees_earned(e: &Env) -> i128 {
     e.storage().instance().get(&DataKey::FeesEarned).unwrap_or(0)
 }
 
-fn get_balance_shares(e: &Env) -> i128 {
-    get_balance(e, get_token_share(e))
+fn get_outstanding_fees(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::OutstandingFees).unwrap_or(0)
+}
+
+fn get_accrued_fees(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::AccruedFees).unwrap_or(0)
+}
+
+fn get_fee_accrual_rate(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::FeeAccrualRate).unwrap_or(0)
+}
+
+fn get_fee_maturities(e: &Env) -> Map<u64, i128> {
+    e.storage().instance().get(&DataKey::FeeMaturities).unwrap_or(Map::new(e))
+}
+
+fn get_last_accrual(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::LastAccrual).unwrap_or(e.ledger().timestamp())
+}
+
+fn get_impairments(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::Impairments).unwrap_or(0)
+}
+
+fn get_grace_period(e: &Env) -> u64 {
+    read_config(e).grace_period
+}
+
+fn get_penalty_rate(e: &Env) -> i128 {
+    read_config(e).penalty_apr_bps
+}
+
+fn get_default_window(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::DefaultWindow).unwrap_or(DEFAULT_DEFAULT_WINDOW)
+}
+
+fn get_senior_rate(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::SeniorRate).unwrap_or(DEFAULT_SENIOR_APR_BPS)
+}
+
+fn get_min_junior_coverage(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::MinJuniorCoverage).unwrap_or(DEFAULT_MIN_JUNIOR_COVERAGE_BPS)
+}
+
+fn get_endorsement_threshold(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::EndorsementThreshold).unwrap_or(0)
+}
+
+fn get_endorsement_quorum(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::EndorsementQuorum).unwrap_or(DEFAULT_ENDORSEMENT_QUORUM)
+}
+
+fn get_lockup_period(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::LockupPeriod).unwrap_or(0)
+}
+
+// None blocks withdrawals inside the lockup, otherwise they pay this fee in bps
+fn get_early_exit_fee(e: &Env) -> Option<i128> {
+    e.storage().instance().get(&DataKey::EarlyExitFee).unwrap_or(None)
+}
+
+fn get_balance_shares_of(e: &Env, tranche: Tranche, who: &Address) -> i128 {
+    token::Client::new(e, &get_token_share(e, tranche)).balance(who)
 }
 
 fn get_balance_usdc(e: &Env) -> i128 {
-    get_balance(e, get_usdc(e))
+    token::Client::new(e, &get_usdc(e)).balance(&e.current_contract_address())
+}
+
+fn get_withdraw_reserve(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::WithdrawReserve).unwrap_or(0)
+}
+
+fn get_queued_shares(e: &Env, tranche: Tranche) -> i128 {
+    e.storage().instance().get(&tranche.queued_shares_key()).unwrap_or(0)
+}
+
+// USDC the pool can lend or pay out, excluding what funded withdrawals are
+// owed. Tracked on every flow in and out rather than read from the token
+// balance, so USDC sent straight to the contract never moves the share price.
+fn get_idle_assets(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::IdleAssets).unwrap_or(0)
 }
 
 fn put_token_usdc(e: &Env, contract: Address) {
     e.storage().instance().set(&DataKey::Usdc, &contract);
 }
 
-fn put_token_share(e: &Env, contract: Address) {
-    e.storage().instance().set(&DataKey::TokenShare, &contract);
+fn put_token_share(e: &Env, tranche: Tranche, contract: Address) {
+    e.storage().instance().set(&tranche.token_share_key(), &contract);
 }
 
 fn put_insurance_address(e: &Env, contract: Address) {
     e.storage().instance().set(&DataKey::InsuranceAddress, &contract);
 }
 
-fn put_total_shares(e: &Env, amount: i128) {
-    e.storage().instance().set(&DataKey::TotalShares, &amount)
+fn put_total_shares(e: &Env, tranche: Tranche, amount: i128) {
+    e.storage().instance().set(&tranche.total_shares_key(), &amount)
 }
 
 fn put_total_loan_amount(e: &Env, amount: i128) {
@@ -123,218 +331,1851 @@ fn put_total_outstanding_loan(e: &Env, amount: i128) {
     e.storage().instance().set(&DataKey::TotalOutstandingLoan, &amount)
 }
 
-fn burn_shares(e: &Env, amount: i128) {
-    let total = get_total_shares(e);
-    let share_contract = get_token_share(e);
+fn put_default_window(e: &Env, window: u64) {
+    e.storage().instance().set(&DataKey::DefaultWindow, &window)
+}
+
+fn put_senior_rate(e: &Env, apr_bps: i128) {
+    e.storage().instance().set(&DataKey::SeniorRate, &apr_bps)
+}
+
+fn put_min_junior_coverage(e: &Env, coverage_bps: i128) {
+    e.storage().instance().set(&DataKey::MinJuniorCoverage, &coverage_bps)
+}
+
+fn put_endorsement_terms(e: &Env, threshold: i128, quorum: u32) {
+    e.storage().instance().set(&DataKey::EndorsementThreshold, &threshold);
+    e.storage().instance().set(&DataKey::EndorsementQuorum, &quorum);
+}
+
+// Underwriter sign-offs a loan needs before it is approved
+fn endorsements_required(e: &Env, loan: &LoanDetails) -> u32 {
+    if loan.invoice_amount > get_endorsement_threshold(e) {
+        get_endorsement_quorum(e)
+    } else {
+        1
+    }
+}
+
+// Approve the loan once enough underwriters have endorsed its terms
+fn approve_if_endorsed(e: &Env, loan_id: u64, loan: &mut LoanDetails) -> Result<(), Error> {
+    if loan.endorsements.len() < endorsements_required(e, loan) {
+        write_loan(e, loan_id, loan);
+        e.events()
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(e, "loan_endorsed"), loan.status), loan.clone());
+        return Ok(());
+    }
+    loan.transition(LoanStatus::Approved)?;
+    write_loan(e, loan_id, loan);
+    e.events()
+        .publish((AdminDataKey::LOAN(loan_id), Symbol::new(e, "loan_approved"), loan.status), loan.clone());
+    Ok(())
+}
+
+fn put_lockup_terms(e: &Env, lockup_period: u64, early_exit_fee_bps: Option<i128>) {
+    e.storage().instance().set(&DataKey::LockupPeriod, &lockup_period);
+    e.storage().instance().set(&DataKey::EarlyExitFee, &early_exit_fee_bps);
+}
+
+fn put_idle_assets(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::IdleAssets, &amount)
+}
+
+// USDC held beyond what the pool tracks: donations, stray transfers
+fn balance_check(e: &Env) -> BalanceCheck {
+    let idle_assets = get_idle_assets(e);
+    let withdraw_reserve = get_withdraw_reserve(e);
+    let protocol_fees = get_protocol_fees(e);
+    let balance = get_balance_usdc(e);
+    BalanceCheck {
+        idle_assets,
+        withdraw_reserve,
+        protocol_fees,
+        balance,
+        surplus: balance - idle_assets - withdraw_reserve - protocol_fees,
+    }
+}
+
+fn get_protocol_fees(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::ProtocolFees).unwrap_or(0)
+}
+
+fn put_protocol_fees(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::ProtocolFees, &amount)
+}
+
+fn put_withdraw_reserve(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::WithdrawReserve, &amount)
+}
+
+fn get_parked_junior_shares(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::ParkedJuniorShares).unwrap_or(0)
+}
+
+fn put_parked_junior_shares(e: &Env, share_amount: i128) {
+    e.storage().instance().set(&DataKey::ParkedJuniorShares, &share_amount)
+}
+
+fn put_queued_shares(e: &Env, tranche: Tranche, share_amount: i128) {
+    e.storage().instance().set(&tranche.queued_shares_key(), &share_amount)
+}
+
+fn put_impairments(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::Impairments, &amount)
+}
+
+fn put_outstanding_fees(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::OutstandingFees, &amount)
+}
+
+fn put_accrued_fees(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::AccruedFees, &amount)
+}
+
+fn put_fee_accrual_rate(e: &Env, rate: i128) {
+    e.storage().instance().set(&DataKey::FeeAccrualRate, &rate)
+}
+
+fn put_fee_maturities(e: &Env, maturities: &Map<u64, i128>) {
+    e.storage().instance().set(&DataKey::FeeMaturities, maturities)
+}
+
+fn put_last_accrual(e: &Env, timestamp: u64) {
+    e.storage().instance().set(&DataKey::LastAccrual, &timestamp)
+}
+
+// Portion of a loan's fee that belongs to LPs once the insurance and protocol
+// cuts are taken
+fn lp_fee(loan: &LoanDetails) -> i128 {
+    loan.lp_share(loan.fee())
+}
+
+// Per-second accrual rate of a released loan's LP fee, scaled by ACCRUAL_SCALE.
+// Rounded up so the fee has fully accrued by the repayment date, the cap in
+// `accrued_fees_now` absorbs the overshoot.
+fn loan_accrual_rate(loan: &LoanDetails) -> i128 {
+    if loan.repayment_date <= loan.released_at {
+        return 0;
+    }
+    div_ceil(lp_fee(loan) * ACCRUAL_SCALE, (loan.repayment_date - loan.released_at) as i128)
+}
+
+// LP fee a released loan has earned so far, linear over its tenor
+fn loan_accrued_fee(e: &Env, loan: &LoanDetails) -> i128 {
+    let now = e.ledger().timestamp();
+    if now >= loan.repayment_date || loan.repayment_date <= loan.released_at {
+        return lp_fee(loan);
+    }
+    lp_fee(loan) * (now - loan.released_at) as i128 / (loan.repayment_date - loan.released_at) as i128
+}
+
+// Fees accrued on the outstanding book up to now, with the accrual rate left
+// once the loans maturing by now stop accruing. Each loan accrues until its
+// repayment date, an overdue loan earns nothing more until it is repaid.
+fn accrual_now(e: &Env) -> (i128, i128) {
+    let now = e.ledger().timestamp();
+    let mut last = get_last_accrual(e);
+    let mut rate = get_fee_accrual_rate(e);
+    let mut accrued = 0;
+    for (maturity, maturing_rate) in get_fee_maturities(e).iter() {
+        if maturity > now {
+            break;
+        }
+        accrued += rate * maturity.saturating_sub(last) as i128;
+        last = last.max(maturity);
+        rate -= maturing_rate;
+    }
+    accrued += rate * now.saturating_sub(last) as i128;
+    let accrued = get_accrued_fees(e) + accrued / ACCRUAL_SCALE;
+    (accrued.min(get_outstanding_fees(e)), rate)
+}
+
+fn accrued_fees_now(e: &Env) -> i128 {
+    accrual_now(e).0
+}
+
+fn accrue_fees(e: &Env) {
+    let now = e.ledger().timestamp();
+    let (accrued, rate) = accrual_now(e);
+    put_accrued_fees(e, accrued);
+    put_fee_accrual_rate(e, rate);
+    put_last_accrual(e, now);
+
+    let mut maturities = get_fee_maturities(e);
+    while let Some(maturity) = maturities.keys().first() {
+        if maturity > now {
+            break;
+        }
+        maturities.remove(maturity);
+    }
+    put_fee_maturities(e, &maturities);
+}
+
+// Add a released loan's rate to the accrual until its repayment date
+fn start_fee_accrual(e: &Env, loan: &LoanDetails) {
+    let rate = loan_accrual_rate(loan);
+    put_fee_accrual_rate(e, get_fee_accrual_rate(e) + rate);
+    let mut maturities = get_fee_maturities(e);
+    maturities.set(loan.repayment_date, maturities.get(loan.repayment_date).unwrap_or(0) + rate);
+    put_fee_maturities(e, &maturities);
+}
+
+// Take a loan that is repaid or defaulted out of the fee accrual. Whatever it
+// accrued beyond the fee already realised leaves NAV with it.
+fn stop_fee_accrual(e: &Env, loan: &LoanDetails) {
+    accrue_fees(e);
+    let lp_fee_realised = loan.lp_share(loan.fee_paid);
+    put_accrued_fees(e, get_accrued_fees(e) + lp_fee_realised - loan_accrued_fee(e, loan));
+    put_outstanding_fees(e, get_outstanding_fees(e) - (lp_fee(loan) - lp_fee_realised));
+    // A loan past its repayment date already left the accrual rate
+    if loan.repayment_date > e.ledger().timestamp() {
+        let rate = loan_accrual_rate(loan);
+        put_fee_accrual_rate(e, get_fee_accrual_rate(e) - rate);
+        let mut maturities = get_fee_maturities(e);
+        let maturing_rate = maturities.get(loan.repayment_date).unwrap_or(0) - rate;
+        if maturing_rate == 0 {
+            maturities.remove(loan.repayment_date);
+        } else {
+            maturities.set(loan.repayment_date, maturing_rate);
+        }
+        put_fee_maturities(e, &maturities);
+    }
+    if get_fee_accrual_rate(e) == 0 && get_outstanding_fees(e) == 0 {
+        // Nothing left accruing, drop any rounding residue
+        put_accrued_fees(e, 0);
+    }
+}
+
+// Claim a written-off loan's loss from the insurance reserve. Returns what
+// it covered, nothing if the reserve fails so the write-off still goes ahead
+// and LPs take the loss.
+fn draw_insurance(e: &Env, loan_id: u64, loss: i128) -> i128 {
+    if loss <= 0 {
+        return 0;
+    }
+    let covered = match insurance::Client::new(e, &get_insurance_address(e)).try_claim(&loan_id, &loss) {
+        Ok(Ok(covered)) => covered.clamp(0, loss),
+        _ => {
+            e.events().publish((AdminDataKey::LOAN(loan_id), Symbol::new(e, "insurance_unavailable")), loss);
+            0
+        }
+    };
+    put_idle_assets(e, get_idle_assets(e) + covered);
+    covered
+}
+
+// Net asset value of the whole pool: idle USDC, principal lent out and fees
+// accrued on it, less any impairments
+fn get_nav(e: &Env) -> i128 {
+    get_idle_assets(e) + get_total_outstanding_loan(e) + accrued_fees_now(e) - get_impairments(e)
+}
+
+// What the senior tranche is owed, its shares at the senior index
+fn senior_claim(e: &Env) -> i128 {
+    get_total_shares(e, Tranche::Senior) * senior_index_now(e, get_senior_rate(e)) / SENIOR_INDEX_SCALE
+}
+
+// Share of NAV backing a tranche. Seniors are paid first up to their claim,
+// juniors get the rest and so take any loss first.
+fn get_tranche_nav(e: &Env, tranche: Tranche) -> i128 {
+    let nav = get_nav(e).max(0);
+    let senior_nav = senior_claim(e).min(nav);
+    match tranche {
+        Tranche::Senior => senior_nav,
+        Tranche::Junior => nav - senior_nav,
+    }
+}
+
+// Share price, scaled by SENIOR_INDEX_SCALE, of the virtual shares. Junior
+// shares start at 1 USDC, senior shares at the senior index so a new senior
+// does not earn yield accrued before them.
+fn empty_share_price(e: &Env, tranche: Tranche) -> i128 {
+    match tranche {
+        Tranche::Senior => senior_index_now(e, get_senior_rate(e)),
+        Tranche::Junior => SENIOR_INDEX_SCALE,
+    }
+}
+
+// Tranche shares and NAV the share price is taken from, both including
+// VIRTUAL_SHARES backed at the empty share price. A first depositor can never
+// hold the whole supply, so an exit fee they leave behind mostly goes to the
+// virtual shares rather than pushing up the price of a last share or two.
+fn share_price_basis(e: &Env, tranche: Tranche) -> (i128, i128) {
+    let total_shares = get_total_shares(e, tranche) + VIRTUAL_SHARES;
+    let virtual_assets = VIRTUAL_SHARES * empty_share_price(e, tranche) / SENIOR_INDEX_SCALE;
+    (total_shares, get_tranche_nav(e, tranche) + virtual_assets)
+}
+
+// Shares minted for a deposit of `amount`, rounded down in favour of the pool
+fn shares_for_assets(e: &Env, tranche: Tranche, amount: i128) -> i128 {
+    let (total_shares, nav) = share_price_basis(e, tranche);
+    total_shares * amount / nav
+}
+
+// Assets paid out for `share_amount`, rounded down in favour of the pool
+fn assets_for_shares(e: &Env, tranche: Tranche, share_amount: i128) -> i128 {
+    let (total_shares, nav) = share_price_basis(e, tranche);
+    share_amount * nav / total_shares
+}
+
+// Shares burnt to pay out `amount`, rounded up in favour of the pool
+fn shares_for_assets_up(e: &Env, tranche: Tranche, amount: i128) -> i128 {
+    let (total_shares, nav) = share_price_basis(e, tranche);
+    div_ceil(total_shares * amount, nav)
+}
+
+// Assets charged to mint `share_amount`, rounded up in favour of the pool
+fn assets_for_shares_up(e: &Env, tranche: Tranche, share_amount: i128) -> i128 {
+    let (total_shares, nav) = share_price_basis(e, tranche);
+    div_ceil(share_amount * nav, total_shares)
+}
+
+// Junior NAV as a share of the pool's NAV, in basis points
+fn junior_coverage(e: &Env) -> i128 {
+    let nav = get_nav(e).max(0);
+    if nav == 0 {
+        return BPS_DENOMINATOR;
+    }
+    get_tranche_nav(e, Tranche::Junior) * BPS_DENOMINATOR / nav
+}
+
+// Most USDC juniors can take out while still covering the minimum share of
+// NAV, unbounded when there are no seniors to cover
+fn junior_withdrawable(e: &Env) -> i128 {
+    let nav = get_nav(e).max(0);
+    let junior_nav = get_tranche_nav(e, Tranche::Junior);
+    if junior_nav == nav {
+        return junior_nav;
+    }
+    let min_coverage = get_min_junior_coverage(e);
+    let headroom = junior_nav * BPS_DENOMINATOR - min_coverage * nav;
+    if headroom <= 0 {
+        return 0;
+    }
+    // Only reachable with min_coverage below 100%
+    headroom / (BPS_DENOMINATOR - min_coverage)
+}
+
+// Juniors must still cover the minimum share of NAV once `junior_out` USDC
+// has left their tranche, unless there are no seniors left to cover
+fn check_junior_coverage(e: &Env, junior_out: i128) -> Result<(), Error> {
+    let nav = get_nav(e).max(0) - junior_out;
+    let junior_nav = get_tranche_nav(e, Tranche::Junior) - junior_out;
+    if junior_nav < nav && junior_nav * BPS_DENOMINATOR < get_min_junior_coverage(e) * nav {
+        return Err(Error::InsufficientJuniorCoverage);
+    }
+    Ok(())
+}
+
+// Fund a pending request at the share price now, the shares stop sharing in
+// NAV. Fails without touching it when the idle USDC or, for juniors, the
+// minimum coverage does not allow it yet.
+fn fund_request(e: &Env, request_id: u64, request: &mut WithdrawRequest) -> Result<(), Error> {
+    let gross_amount = assets_for_shares(e, request.tranche, request.share_amount);
+    let locked_amount = assets_for_shares(e, request.tranche, request.locked_shares);
+    let fee = exit_fee(locked_amount, request.exit_fee_bps);
+    let amount = gross_amount - fee;
+    if amount > get_idle_assets(e) {
+        return Err(Error::InsufficientLiquidity);
+    }
+    if request.tranche == Tranche::Junior {
+        check_junior_coverage(e, amount)?;
+    }
+    burn_shares(e, request.tranche, request.share_amount);
+    retain_exit_fee(e, request.tranche, fee);
+    put_queued_shares(e, request.tranche, get_queued_shares(e, request.tranche) - request.share_amount);
+    put_idle_assets(e, get_idle_assets(e) - amount);
+    put_withdraw_reserve(e, get_withdraw_reserve(e) + amount);
+
+    request.amount = amount;
+    request.status = WithdrawStatus::Funded;
+    write_withdraw_request(e, request_id, request);
+    e.events()
+        .publish((AdminDataKey::WITHDRAWAL(request_id), Symbol::new(e, "withdraw_funded"), request.status), request.clone());
+    Ok(())
+}
+
+// Fund queued withdrawals in request order from idle USDC, reading at most
+// MAX_QUEUE_ENTRIES_PER_CALL entries. `process_withdrawals` carries on from
+// there. The first request short of liquidity stops the walk, so later
+// requests never jump the queue. A junior request held back by the minimum
+// coverage is parked instead, together with every junior request behind it,
+// and seniors keep being funded. Parked requests are the oldest left, they
+// are tried first on every call.
+fn fund_withdrawals(e: &Env) {
+    let mut budget = MAX_QUEUE_ENTRIES_PER_CALL;
+    let mut parked_head = get_parked_head(e);
+    let parked_end = next_parked_slot(e);
+    while parked_head < parked_end && budget > 0 {
+        budget -= 1;
+        if let Some(request_id) = read_parked_request(e, parked_head) {
+            if let Ok(mut request) = read_withdraw_request(e, request_id) {
+                if request.status == WithdrawStatus::Pending {
+                    match fund_request(e, request_id, &mut request) {
+                        Ok(()) => put_parked_junior_shares(e, get_parked_junior_shares(e) - request.share_amount),
+                        Err(Error::InsufficientLiquidity) => {
+                            put_parked_head(e, parked_head);
+                            return;
+                        }
+                        Err(_) => break,
+                    }
+                }
+            }
+            remove_parked_request(e, parked_head);
+        }
+        parked_head += 1;
+    }
+    put_parked_head(e, parked_head);
+    let mut juniors_parked = parked_head < parked_end;
+
+    let mut head = get_queue_head(e);
+    let end = next_withdraw_id(e).min(head + budget);
+    while head < end {
+        let mut request = match read_withdraw_request(e, head) {
+            Ok(request) => request,
+            Err(_) => break,
+        };
+        if request.status == WithdrawStatus::Pending {
+            let funded = if request.tranche == Tranche::Junior && juniors_parked {
+                Err(Error::InsufficientJuniorCoverage)
+            } else {
+                fund_request(e, head, &mut request)
+            };
+            match funded {
+                Ok(()) => {}
+                Err(Error::InsufficientLiquidity) => break,
+                Err(_) => {
+                    park_request(e, head);
+                    put_parked_junior_shares(e, get_parked_junior_shares(e) + request.share_amount);
+                    juniors_parked = true;
+                }
+            }
+        }
+        head += 1;
+    }
+    put_queue_head(e, head);
+}
+
+// Whether a queued withdrawal in `tranche` is only waiting for liquidity.
+// Parked juniors do not hold up the tranche, they wait on the coverage.
+fn has_pending_withdrawals(e: &Env, tranche: Tranche) -> bool {
+    let mut queued_shares = get_queued_shares(e, tranche);
+    if tranche == Tranche::Junior {
+        queued_shares -= get_parked_junior_shares(e);
+    }
+    queued_shares > 0
+}
+
+fn div_ceil(numerator: i128, denominator: i128) -> i128 {
+    (numerator + denominator - 1) / denominator
+}
+
+// Pull `amount` USDC from the depositor and mint them `share_amount` tranche
+// shares. Inside a lockup the pool holds the shares until it ends.
+fn deposit_assets(e: &Env, tranche: Tranche, from: Address, amount: i128, share_amount: i128) {
+    let lockup_period = get_lockup_period(e);
+    if lockup_period == 0 {
+        mint_shares(e, tranche, from.clone(), share_amount);
+    } else {
+        mint_shares(e, tranche, e.current_contract_address(), share_amount);
+        let unlocks_at = e.ledger().timestamp().saturating_add(lockup_period);
+        lock_shares(e, &from, tranche, share_amount, unlocks_at);
+    }
+
+    let usdc_client = token::Client::new(e, &get_usdc(e));
+    usdc_client.transfer(&from, &e.current_contract_address(), &amount);
+    put_idle_assets(e, get_idle_assets(e) + amount);
+}
+
+// Burn `share_amount` tranche shares escrowed from the holder and pay them
+// `amount` USDC
+fn redeem_shares(e: &Env, tranche: Tranche, to: Address, share_amount: i128, amount: i128) {
+    burn_shares(e, tranche, share_amount);
+    transfer(e, get_usdc(e), to, amount);
+    put_idle_assets(e, get_idle_assets(e) - amount);
+}
+
+fn is_initialized(e: &Env) -> bool {
+    e.storage().instance().has(&DataKey::Usdc)
+}
+
+// Every entrypoint goes through here, so this also keeps the instance alive.
+// After an upgrade the pool stays closed until `migrate` has run.
+fn check_initialized(e: &Env) -> Result<(), Error> {
+    check_deployed(e)?;
+    if get_schema_version(e) != SCHEMA_VERSION {
+        return Err(Error::MigrationRequired);
+    }
+    Ok(())
+}
+
+// Initialized, whatever the layout its storage is on
+fn check_deployed(e: &Env) -> Result<(), Error> {
+    if !is_initialized(e) {
+        return Err(Error::NotInitialized);
+    }
+    e.storage().instance().extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+    Ok(())
+}
+
+fn check_amount(amount: i128) -> Result<(), Error> {
+    if amount <= 0 {
+        return Err(Error::InvalidAmount);
+    }
+    Ok(())
+}
+
+// Deadlines are ledger timestamps, the call fails once one has passed
+fn check_deadline(e: &Env, deadline: Option<u64>) -> Result<(), Error> {
+    match deadline {
+        Some(deadline) if e.ledger().timestamp() > deadline => Err(Error::DeadlinePassed),
+        _ => Ok(()),
+    }
+}
+
+fn check_bps(bps: i128) -> Result<(), Error> {
+    if !(0..=BPS_DENOMINATOR).contains(&bps) {
+        return Err(Error::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn check_apr(e: &Env, apr_bps: i128) -> Result<(), Error> {
+    if apr_bps < 0 {
+        return Err(Error::InvalidAmount);
+    }
+    if apr_bps > read_config(e).max_apr_bps {
+        return Err(Error::FeeRateTooHigh);
+    }
+    Ok(())
+}
+
+// Reject a change when it is queued rather than once the delay has passed
+fn check_change(e: &Env, change: &ParamChange) -> Result<(), Error> {
+    match change {
+        ParamChange::PoolConfig(config) => config.validate(),
+        ParamChange::LockupTerms(_, Some(fee_bps)) => check_bps(*fee_bps),
+        ParamChange::TrancheTerms(senior_apr_bps, min_junior_coverage_bps) => {
+            check_apr(e, *senior_apr_bps)?;
+            check_bps(*min_junior_coverage_bps)
+        }
+        ParamChange::EndorsementTerms(threshold, quorum) if *threshold < 0 || *quorum == 0 => {
+            Err(Error::InvalidAmount)
+        }
+        _ => Ok(()),
+    }
+}
+
+// The advance has to be positive, a discount can eat the whole invoice on a
+// long enough tenor, and cannot exceed the configured share of the invoice
+fn check_advance(e: &Env, loan: &LoanDetails) -> Result<(), Error> {
+    if loan.loan_amount <= 0 {
+        return Err(Error::InvalidAdvance);
+    }
+    if loan.loan_amount * BPS_DENOMINATOR > loan.invoice_amount * read_config(e).max_advance_bps {
+        return Err(Error::AdvanceRateTooHigh);
+    }
+    Ok(())
+}
+
+// Add shares the pool already holds to `who`'s lockups, unlocking at
+// `unlocks_at`. Matured locked shares are handed over first, shares locked
+// earlier keep their own unlock time.
+fn lock_shares(e: &Env, who: &Address, tranche: Tranche, share_amount: i128, unlocks_at: u64) {
+    release_unlocked(e, who, tranche);
+    if e.ledger().timestamp() >= unlocks_at {
+        token::Client::new(e, &get_token_share(e, tranche)).transfer(&e.current_contract_address(), who, &share_amount);
+        return;
+    }
+    let mut lockups = read_lockups(e, who, tranche);
+    add_lockup(&mut lockups, Lockup { share_amount, unlocks_at });
+    write_lockups(e, who, tranche, &lockups);
+}
+
+// Hand `who` their locked shares whose lockup is over, returns how many
+fn release_unlocked(e: &Env, who: &Address, tranche: Tranche) -> i128 {
+    let mut lockups = read_lockups(e, who, tranche);
+    let mut released = 0;
+    while let Some(lockup) = lockups.first() {
+        if !lockup.is_expired(e.ledger().timestamp()) {
+            break;
+        }
+        released += lockup.share_amount;
+        lockups.pop_front();
+    }
+    if released > 0 {
+        let share_token_client = token::Client::new(e, &get_token_share(e, tranche));
+        share_token_client.transfer(&e.current_contract_address(), who, &released);
+        write_lockups(e, who, tranche, &lockups);
+    }
+    released
+}
+
+// Move `share_amount` of `who`'s shares into the pool's hands, the shares they
+// hold first and then their locked ones, those unlocking last first. Returns
+// the lockups taken and the early exit fee in bps due on them.
+fn escrow_shares(e: &Env, who: &Address, tranche: Tranche, share_amount: i128) -> Result<(Vec<Lockup>, i128), Error> {
+    release_unlocked(e, who, tranche);
+    let held = get_balance_shares_of(e, tranche, who).min(share_amount);
+    let mut lockups = read_lockups(e, who, tranche);
+    let mut locked_shares = share_amount - held;
+    if locked_shares > total_locked(&lockups) {
+        return Err(Error::InsufficientShares);
+    }
+    let exit_fee_bps = match locked_shares {
+        0 => 0,
+        _ => get_early_exit_fee(e).ok_or(Error::LockupNotExpired)?,
+    };
+
+    if held > 0 {
+        let share_token_client = token::Client::new(e, &get_token_share(e, tranche));
+        share_token_client.transfer(who, &e.current_contract_address(), &held);
+    }
+    let mut taken = Vec::new(e);
+    while locked_shares > 0 {
+        let mut lockup = lockups.pop_back().unwrap();
+        let share_amount = lockup.share_amount.min(locked_shares);
+        lockup.share_amount -= share_amount;
+        locked_shares -= share_amount;
+        taken.push_front(Lockup { share_amount, unlocks_at: lockup.unlocks_at });
+        if lockup.share_amount > 0 {
+            lockups.push_back(lockup);
+        }
+    }
+    write_lockups(e, who, tranche, &lockups);
+    Ok((taken, exit_fee_bps))
+}
+
+// Fee charged on `amount`, rounded up in favour of the LPs staying in
+fn exit_fee(amount: i128, exit_fee_bps: i128) -> i128 {
+    div_ceil(amount * exit_fee_bps, BPS_DENOMINATOR)
+}
+
+// An exit fee stays with the tranche it was charged in. Juniors own whatever
+// is not owed to seniors already, seniors need their index raised. The index
+// never rises by more than the fee spread over VIRTUAL_SHARES, so a fee cannot
+// land on one last senior share.
+fn retain_exit_fee(e: &Env, tranche: Tranche, fee: i128) {
+    if tranche == Tranche::Senior && fee > 0 {
+        let senior_shares = get_total_shares(e, Tranche::Senior).max(VIRTUAL_SHARES);
+        credit_senior_index(e, get_senior_rate(e), fee, senior_shares);
+    }
+}
+
+fn is_whitelisted(e: &Env, who: &Address) -> bool {
+    let key = AdminDataKey::WHITELISTED(who.clone());
+    let whitelisted = e.storage().persistent().get(&key).unwrap_or(false);
+    if whitelisted {
+        e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    }
+    whitelisted
+}
+
+fn write_whitelisted(e: &Env, who: &Address) {
+    let key = AdminDataKey::WHITELISTED(who.clone());
+    e.storage().persistent().set(&key, &true);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+fn burn_shares(e: &Env, tranche: Tranche, amount: i128) {
+    let total = get_total_shares(e, tranche);
+    let share_contract = get_token_share(e, tranche);
 
     token::Client::new(e, &share_contract).burn(&e.current_contract_address(), &amount);
-    put_total_shares(e, total - amount);
+    put_total_shares(e, tranche, total - amount);
 }
 
-fn mint_shares(e: &Env, to: Address, amount: i128) {
-    let total = get_total_shares(e);
-    let share_contract_id = get_token_share(e);
+fn mint_shares(e: &Env, tranche: Tranche, to: Address, amount: i128) {
+    let total = get_total_shares(e, tranche);
+    let share_contract_id = get_token_share(e, tranche);
 
     token::Client::new(e, &share_contract_id).mint(&to, &amount);
 
-    put_total_shares(e, total + amount);
+    put_total_shares(e, tranche, total + amount);
 }
 
 fn transfer(e: &Env, token: Address, to: Address, amount: i128) {
     token::Client::new(e, &token).transfer(&e.current_contract_address(), &to, &amount);
 }
 
+fn approve(e: &Env, operator: Address, loan_id: u64, apr_bps: i128, installments: Option<Vec<Installment>>) -> Result<(), Error> {
+    check_initialized(e)?;
+    check_not_paused(e, Operation::ApproveLoan)?;
+    check_role(e, Role::Underwriter, &operator)?;
+
+    check_apr(e, apr_bps)?;
+
+    let mut loan = read_loan(e, loan_id)?;
+    loan.status.check_transition(LoanStatus::Approved)?;
+    if let Some(installments) = installments {
+        check_schedule(&loan, &installments, e.ledger().timestamp())?;
+        loan.installments = installments;
+    }
+    let config = read_config(e);
+    loan.apr_bps = apr_bps;
+    loan.grace_period = config.grace_period;
+    loan.penalty_apr_bps = get_penalty_rate(e);
+    loan.insurance_fee_bps = config.insurance_fee_bps;
+    loan.protocol_fee_bps = config.protocol_fee_bps;
+    // Indicative advance if claimed now
+    loan.price(e.ledger().timestamp());
+    check_advance(e, &loan)?;
+    // New terms need fresh endorsements, the approver's counts as the first
+    loan.endorsements = vec![e, operator];
+
+    approve_if_endorsed(e, loan_id, &mut loan)
+}
+
+// Collect `amount` from the borrower and apply it to the loan, moving it to
+// Repaid once nothing is left to pay. Returns the cut sent to insurance.
+fn collect_repayment(e: &Env, loan_id: u64, loan: &mut LoanDetails, amount: i128) -> Result<i128, Error> {
+    let usdc_client = token::Client::new(e, &get_usdc(e));
+    usdc_client.transfer(&loan.who, &e.current_contract_address(), &amount);
+    put_idle_assets(e, get_idle_assets(e) + amount);
+
+    accrue_fees(e);
+    let (fee_paid, penalty_paid) = (loan.fee_paid, loan.penalty_paid);
+    let (_, _, principal) = loan.apply_payment(e.ledger().timestamp(), amount);
+
+    // Principal goes back to the idle balance
+    put_total_outstanding_loan(e, get_total_outstanding_loan(e) - principal);
+
+    // The LP share of the fee is realised and leaves the accrual. The penalty
+    // never accrued, it is split the same way as the fee.
+    let lp_fee_paid = loan.lp_share(loan.fee_paid) - loan.lp_share(fee_paid);
+    let lp_penalty_paid = loan.lp_share(loan.penalty_paid) - loan.lp_share(penalty_paid);
+    let fee_to_insurance = loan.insurance_cut(loan.fee_paid) - loan.insurance_cut(fee_paid);
+    let penalty_to_insurance = loan.insurance_cut(loan.penalty_paid) - loan.insurance_cut(penalty_paid);
+    put_accrued_fees(e, get_accrued_fees(e) - lp_fee_paid);
+    put_outstanding_fees(e, get_outstanding_fees(e) - lp_fee_paid);
+    put_fees_earned(e, get_fees_earned(e) + lp_fee_paid + lp_penalty_paid);
+
+    // The protocol cut stays in the contract until the treasury collects it
+    let fees_to_protocol = loan.protocol_cut(loan.fee_paid) - loan.protocol_cut(fee_paid)
+        + loan.protocol_cut(loan.penalty_paid)
+        - loan.protocol_cut(penalty_paid);
+    if fees_to_protocol > 0 {
+        put_protocol_fees(e, get_protocol_fees(e) + fees_to_protocol);
+        put_idle_assets(e, get_idle_assets(e) - fees_to_protocol);
+    }
+
+    let fees_to_insurance = fee_to_insurance + penalty_to_insurance;
+    if fees_to_insurance > 0 {
+        let insurance = get_insurance_address(e);
+        transfer(e, get_usdc(e), insurance.clone(), fees_to_insurance);
+        put_idle_assets(e, get_idle_assets(e) - fees_to_insurance);
+        // The reserve only records the premium, a failing or missing insurance
+        // contract must never block a repayment
+        let recorded = insurance::Client::new(e, &insurance).try_receive_premium(&loan_id, &fees_to_insurance);
+        if !matches!(recorded, Ok(Ok(()))) {
+            e.events()
+                .publish((AdminDataKey::LOAN(loan_id), Symbol::new(e, "premium_unrecorded")), fees_to_insurance);
+        }
+    }
+
+    if loan.amount_due(e.ledger().timestamp()) == 0 {
+        loan.transition(LoanStatus::Repaid)?;
+        // Stop accruing the loan's fee, it is now fully realised
+        stop_fee_accrual(e, loan);
+    }
+    write_loan(e, loan_id, loan);
+
+    let event = if loan.status == LoanStatus::Repaid { "loan_repaid" } else { "loan_repayment" };
+    e.events()
+        .publish((AdminDataKey::LOAN(loan_id), Symbol::new(e, event), loan.status), loan.clone());
+
+    // Returned liquidity goes to queued withdrawals first
+    fund_withdrawals(e);
+    Ok(fees_to_insurance)
+}
+
 #[contract]
 pub struct LumenFinance;
 
 #[contractimpl]
 impl LumenFinance {
-    pub fn initialize(e: Env, token_wasm_hash: BytesN<32>, usdc: Address, admin: Address, insurance: Address) {
-        let share_contract = create_contract(&e, token_wasm_hash, &usdc);
-        token::Client::new(&e, &share_contract).initialize(
+    pub fn initialize(e: Env, token_wasm_hash: BytesN<32>, usdc: Address, admin: Address, insurance: Address) -> Result<(), Error> {
+        if is_initialized(&e) {
+            return Err(Error::AlreadyInitialized);
+        }
+
+        let senior_share_contract = create_contract(&e, token_wasm_hash.clone(), &usdc, Tranche::Senior);
+        token::Client::new(&e, &senior_share_contract).initialize(
+            &e.current_contract_address(),
+            &7u32,
+            &"Get Financed Senior Shares".into_val(&e),
+            &"GFSS".into_val(&e),
+        );
+        let junior_share_contract = create_contract(&e, token_wasm_hash, &usdc, Tranche::Junior);
+        token::Client::new(&e, &junior_share_contract).initialize(
             &e.current_contract_address(),
             &7u32,
-            &"Get Financed Shares".into_val(&e),
-            &"GFS".into_val(&e),
+            &"Get Financed Junior Shares".into_val(&e),
+            &"GFSJ".into_val(&e),
         );
 
         write_administrator(&e, &admin);
         put_token_usdc(&e, usdc);
-        put_token_share(&e, share_contract);
+        put_token_share(&e, Tranche::Senior, senior_share_contract);
+        put_token_share(&e, Tranche::Junior, junior_share_contract);
         put_insurance_address(&e, insurance);
-        put_total_shares(&e, 0);
+        put_total_shares(&e, Tranche::Senior, 0);
+        put_total_shares(&e, Tranche::Junior, 0);
+        put_schema_version(&e, SCHEMA_VERSION);
+        Ok(())
     }
 
-    // Deposit and get shares
-    pub fn deposit(e: Env, from: Address, amount: i128) {
+    // Deposit into a tranche and get its shares, returns the shares minted.
+    // Fails if fewer than `min_shares_out` would be minted or after `deadline`.
+    pub fn deposit(
+        e: Env,
+        from: Address,
+        tranche: Tranche,
+        amount: i128,
+        min_shares_out: i128,
+        deadline: Option<u64>,
+    ) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::Deposit)?;
+        check_amount(amount)?;
+        check_deadline(&e, deadline)?;
         // Depositor needs to authorize the deposit
         from.require_auth();
 
-        // Now calculate how many new pool shares to mint
-        let balance_usdc = get_balance_usdc(&e);
-        if balance_usdc == 0 {
-            mint_shares(&e, from.clone(), amount);
-        } else {
-            let total_shares = get_total_shares(&e);
-            let new_shares =  total_shares * amount / balance_usdc;
-            mint_shares(&e, from.clone(), new_shares);
+        // Now calculate how many new pool shares to mint at the current NAV
+        let new_shares = shares_for_assets(&e, tranche, amount);
+        if new_shares <= 0 {
+            return Err(Error::InvalidAmount);
         }
+        if new_shares < min_shares_out {
+            return Err(Error::SlippageExceeded);
+        }
+        deposit_assets(&e, tranche, from, amount, new_shares);
+        Ok(new_shares)
+    }
 
-        // Transfer the amount to the contract
-        let usdc_client = token::Client::new(&e, &get_usdc(&e));
-        usdc_client.transfer(&from, &e.current_contract_address(), &amount);
+    // Mint an exact amount of shares, returns the USDC pulled from the depositor.
+    // Fails if that is more than `max_assets_in` (0 for no limit) or after
+    // `deadline`.
+    pub fn mint(
+        e: Env,
+        from: Address,
+        tranche: Tranche,
+        share_amount: i128,
+        max_assets_in: i128,
+        deadline: Option<u64>,
+    ) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::Deposit)?;
+        check_amount(share_amount)?;
+        check_deadline(&e, deadline)?;
+        from.require_auth();
+
+        let amount = assets_for_shares_up(&e, tranche, share_amount);
+        if max_assets_in > 0 && amount > max_assets_in {
+            return Err(Error::SlippageExceeded);
+        }
+        deposit_assets(&e, tranche, from, amount, share_amount);
+        Ok(amount)
     }
 
-    // Withdraw based on shares amount
-    pub fn withdraw(e: Env, to: Address, share_amount: i128) {
+    // Withdraw based on shares amount, returns the USDC paid out. Shares still
+    // locked up either cannot leave or pay the early exit fee. Juniors can only
+    // withdraw while they still cover the minimum share of NAV. Fails if less
+    // than `min_assets_out` would be paid or after `deadline`.
+    pub fn withdraw(
+        e: Env,
+        to: Address,
+        tranche: Tranche,
+        share_amount: i128,
+        min_assets_out: i128,
+        deadline: Option<u64>,
+    ) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::Withdraw)?;
+        check_amount(share_amount)?;
+        check_deadline(&e, deadline)?;
         to.require_auth();
 
-        // First transfer the pool shares that need to be redeemed
-        let share_token_client = token::Client::new(&e, &get_token_share(&e));
-        share_token_client.transfer(&to, &e.current_contract_address(), &share_amount);
+        let (locked, exit_fee_bps) = escrow_shares(&e, &to, tranche, share_amount)?;
+        // Queued withdrawals are paid first
+        fund_withdrawals(&e);
+        if has_pending_withdrawals(&e, tranche) {
+            return Err(Error::WithdrawalQueueNotEmpty);
+        }
 
-        let balance_usdc = get_balance_usdc(&e);
-        let balance_shares = get_balance_shares(&e);
-        let total_shares = get_total_shares(&e);
+        // Calculate the withdraw amount at the tranche's current NAV, shares
+        // still locked up pay the early exit fee
+        let gross_amount = assets_for_shares(&e, tranche, share_amount);
+        let fee = exit_fee(assets_for_shares(&e, tranche, total_locked(&locked)), exit_fee_bps);
+        let amount = gross_amount - fee;
+        if amount < min_assets_out {
+            return Err(Error::SlippageExceeded);
+        }
+        if amount > get_idle_assets(&e) {
+            return Err(Error::InsufficientLiquidity);
+        }
+        if tranche == Tranche::Junior {
+            check_junior_coverage(&e, amount)?;
+        }
+        redeem_shares(&e, tranche, to, share_amount, amount);
+        retain_exit_fee(&e, tranche, fee);
+        Ok(amount)
+    }
+
+    // Vault alias of `withdraw`
+    pub fn redeem(
+        e: Env,
+        to: Address,
+        tranche: Tranche,
+        share_amount: i128,
+        min_assets_out: i128,
+        deadline: Option<u64>,
+    ) -> Result<i128, Error> {
+        Self::withdraw(e, to, tranche, share_amount, min_assets_out, deadline)
+    }
+
+    // Queue a withdrawal when the USDC is lent out. The shares are escrowed and
+    // keep sharing in NAV until repayments fund the request, in request order.
+    // Returns the request id.
+    pub fn request_withdraw(e: Env, to: Address, tranche: Tranche, share_amount: i128) -> Result<u64, Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::Withdraw)?;
+        check_amount(share_amount)?;
+        to.require_auth();
+
+        // The lockup applies as of the request
+        let (locked, exit_fee_bps) = escrow_shares(&e, &to, tranche, share_amount)?;
+        put_queued_shares(&e, tranche, get_queued_shares(&e, tranche) + share_amount);
+
+        let request_id = register_withdraw_id(&e);
+        let request = WithdrawRequest {
+            owner: to,
+            tranche,
+            share_amount,
+            amount: 0,
+            locked_shares: total_locked(&locked),
+            lockups: locked,
+            exit_fee_bps,
+            requested_at: e.ledger().timestamp(),
+            status: WithdrawStatus::Pending,
+        };
+        write_withdraw_request(&e, request_id, &request);
+        e.events()
+            .publish((AdminDataKey::WITHDRAWAL(request_id), Symbol::new(&e, "withdraw_requested"), request.status), request);
+
+        // Funded straight away if there is liquidity for it
+        fund_withdrawals(&e);
+        Ok(request_id)
+    }
+
+    // Pay out a funded withdrawal, returns the USDC paid
+    pub fn claim_withdraw(e: Env, request_id: u64) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::Withdraw)?;
+        let mut request = read_withdraw_request(&e, request_id)?;
+        request.owner.require_auth();
+        if request.status != WithdrawStatus::Funded {
+            return Err(Error::WithdrawalNotFunded);
+        }
+
+        put_withdraw_reserve(&e, get_withdraw_reserve(&e) - request.amount);
+        transfer(&e, get_usdc(&e), request.owner.clone(), request.amount);
+
+        request.status = WithdrawStatus::Claimed;
+        write_withdraw_request(&e, request_id, &request);
+        let amount = request.amount;
+        e.events()
+            .publish((AdminDataKey::WITHDRAWAL(request_id), Symbol::new(&e, "withdraw_claimed"), request.status), request);
+        Ok(amount)
+    }
+
+    // Take back the shares of a withdrawal that has not been funded yet
+    pub fn cancel_withdraw(e: Env, request_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        let mut request = read_withdraw_request(&e, request_id)?;
+        request.owner.require_auth();
+        if request.status != WithdrawStatus::Pending {
+            return Err(Error::WithdrawalNotPending);
+        }
+
+        // Shares taken out of the lockup go back into it
+        let held = request.share_amount - request.locked_shares;
+        if held > 0 {
+            let share_token_client = token::Client::new(&e, &get_token_share(&e, request.tranche));
+            share_token_client.transfer(&e.current_contract_address(), &request.owner, &held);
+        }
+        for lockup in request.lockups.iter() {
+            lock_shares(&e, &request.owner, request.tranche, lockup.share_amount, lockup.unlocks_at);
+        }
+        put_queued_shares(&e, request.tranche, get_queued_shares(&e, request.tranche) - request.share_amount);
+        // Behind the queue head it is parked, its slot is dropped once reached
+        if request_id < get_queue_head(&e) {
+            put_parked_junior_shares(&e, get_parked_junior_shares(&e) - request.share_amount);
+        }
+
+        request.status = WithdrawStatus::Cancelled;
+        write_withdraw_request(&e, request_id, &request);
+        e.events()
+            .publish((AdminDataKey::WITHDRAWAL(request_id), Symbol::new(&e, "withdraw_cancelled"), request.status), request);
+        Ok(())
+    }
+
+    // Fund queued withdrawals from idle USDC, callable by anyone
+    pub fn process_withdrawals(e: Env) -> Result<(), Error> {
+        check_initialized(&e)?;
+        fund_withdrawals(&e);
+        Ok(())
+    }
+
+    pub fn get_withdraw_request(e: Env, request_id: u64) -> Result<WithdrawRequest, Error> {
+        check_initialized(&e)?;
+        read_withdraw_request(&e, request_id)
+    }
+
+    // Pending requests ahead of this one in its queue, 0 when it is next to be
+    // funded. A parked junior request only counts the parked ones ahead of it.
+    // Only the first MAX_QUEUE_ENTRIES_PER_CALL entries are read, every entry
+    // beyond them counts as pending, so further back this is an upper bound.
+    pub fn get_queue_position(e: Env, request_id: u64) -> Result<u32, Error> {
+        check_initialized(&e)?;
+        if read_withdraw_request(&e, request_id)?.status != WithdrawStatus::Pending {
+            return Err(Error::WithdrawalNotPending);
+        }
+        let head = get_queue_head(&e);
+        if request_id < head {
+            let parked_head = get_parked_head(&e);
+            let parked_end = next_parked_slot(&e).min(parked_head + MAX_QUEUE_ENTRIES_PER_CALL);
+            let mut position = 0;
+            for slot in parked_head..parked_end {
+                match read_parked_request(&e, slot) {
+                    Some(parked_id) if parked_id == request_id => return Ok(position),
+                    Some(parked_id) if read_withdraw_request(&e, parked_id)?.status == WithdrawStatus::Pending => {
+                        position += 1
+                    }
+                    _ => {}
+                }
+            }
+            return Ok(position + (next_parked_slot(&e) - parked_end) as u32);
+        }
+        let end = request_id.min(head + MAX_QUEUE_ENTRIES_PER_CALL);
+        let mut position = (request_id - end) as u32;
+        for ahead in head..end {
+            if read_withdraw_request(&e, ahead)?.status == WithdrawStatus::Pending {
+                position += 1;
+            }
+        }
+        Ok(position)
+    }
+
+    // Tranche shares escrowed by withdrawals still waiting for liquidity
+    pub fn get_queued_shares(e: Env, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_queued_shares(&e, tranche))
+    }
+
+    // USDC set aside for funded withdrawals not yet claimed
+    pub fn get_withdraw_reserve(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_withdraw_reserve(&e))
+    }
+
+    // Start handing the admin role to `new_admin`, who has to accept it. A
+    // later proposal replaces this one.
+    pub fn propose_admin(e: Env, new_admin: Address) -> Result<(), Error> {
+        check_initialized(&e)?;
+        let admin = read_administrator(&e)?;
+        admin.require_auth();
+
+        write_pending_administrator(&e, &new_admin);
+        e.events().publish((Symbol::new(&e, "admin_proposed"), admin), new_admin);
+        Ok(())
+    }
 
-        // Now calculate the withdraw amounts
-        let withdraw = (balance_usdc * balance_shares) / total_shares;
+    pub fn accept_admin(e: Env) -> Result<(), Error> {
+        check_initialized(&e)?;
+        let new_admin = read_pending_administrator(&e).ok_or(Error::NoPendingAdmin)?;
+        new_admin.require_auth();
 
-        burn_shares(&e, share_amount);
-        transfer(&e, get_usdc(&e), to, withdraw);
+        let previous_admin = read_administrator(&e)?;
+        write_administrator(&e, &new_admin);
+        remove_pending_administrator(&e);
+        e.events().publish((Symbol::new(&e, "admin_accepted"), previous_admin), new_admin);
+        Ok(())
+    }
+
+    // Give up the admin role for good, admin-only entrypoints fail from then on
+    pub fn renounce_admin(e: Env) -> Result<(), Error> {
+        check_initialized(&e)?;
+        let admin = read_administrator(&e)?;
+        admin.require_auth();
+
+        remove_administrator(&e);
+        remove_pending_administrator(&e);
+        e.events().publish((Symbol::new(&e, "admin_renounced"),), admin);
+        Ok(())
+    }
+
+    pub fn get_admin(e: Env) -> Result<Address, Error> {
+        check_initialized(&e)?;
+        read_administrator(&e)
+    }
+
+    pub fn pending_admin(e: Env) -> Result<Option<Address>, Error> {
+        check_initialized(&e)?;
+        Ok(read_pending_administrator(&e))
+    }
+
+    pub fn grant_role(e: Env, role: Role, account: Address) -> Result<(), Error> {
+        check_initialized(&e)?;
+        read_administrator(&e)?.require_auth();
+
+        write_role(&e, role, &account);
+        e.events().publish((Symbol::new(&e, "role_granted"), role), account);
+        Ok(())
+    }
+
+    pub fn revoke_role(e: Env, role: Role, account: Address) -> Result<(), Error> {
+        check_initialized(&e)?;
+        read_administrator(&e)?.require_auth();
+
+        remove_role(&e, role, &account);
+        e.events().publish((Symbol::new(&e, "role_revoked"), role), account);
+        Ok(())
+    }
+
+    // Roles granted explicitly, the admin passes every role check without one
+    pub fn has_role(e: Env, role: Role, account: Address) -> Result<bool, Error> {
+        check_initialized(&e)?;
+        Ok(has_role(&e, role, &account))
+    }
+
+    // Stop deposits, withdrawals and lending at once. Repayments stay open.
+    pub fn pause(e: Env, operator: Address) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::Pauser, &operator)?;
+
+        put_globally_paused(&e, true);
+        e.events().publish((Symbol::new(&e, "paused"),), operator);
+        Ok(())
+    }
+
+    // Lift the global pause, operations switched off on their own stay off
+    pub fn unpause(e: Env, operator: Address) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::Pauser, &operator)?;
+
+        put_globally_paused(&e, false);
+        e.events().publish((Symbol::new(&e, "unpaused"),), operator);
+        Ok(())
+    }
+
+    // Circuit breaker for a single operation
+    pub fn set_operation_paused(e: Env, operator: Address, operation: Operation, paused: bool) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::Pauser, &operator)?;
+
+        put_operation_paused(&e, operation, paused);
+        e.events().publish((Symbol::new(&e, "operation_paused"), operation), paused);
+        Ok(())
+    }
+
+    pub fn is_paused(e: Env) -> Result<bool, Error> {
+        check_initialized(&e)?;
+        Ok(is_globally_paused(&e))
+    }
+
+    // Whether `operation` is currently off, globally or on its own
+    pub fn is_operation_paused(e: Env, operation: Operation) -> Result<bool, Error> {
+        check_initialized(&e)?;
+        Ok(is_globally_paused(&e) || is_operation_paused(&e, operation))
+    }
+
+    // Queue a parameter change, it can be executed once the timelock delay has
+    // passed. Returns the change id.
+    pub fn queue_change(e: Env, change: ParamChange) -> Result<u64, Error> {
+        check_deployed(&e)?;
+        read_administrator(&e)?.require_auth();
+        check_change(&e, &change)?;
+
+        let now = e.ledger().timestamp();
+        let queued = QueuedChange {
+            change,
+            queued_at: now,
+            eta: now + get_timelock_delay(&e),
+            status: ChangeStatus::Queued,
+        };
+        let change_id = next_change_id(&e);
+        write_change(&e, change_id, &queued);
+
+        e.events()
+            .publish((AdminDataKey::CHANGE(change_id), Symbol::new(&e, "change_queued"), queued.status), queued);
+        Ok(change_id)
+    }
+
+    pub fn execute_change(e: Env, change_id: u64) -> Result<(), Error> {
+        check_deployed(&e)?;
+        read_administrator(&e)?.require_auth();
+
+        let mut queued = read_change(&e, change_id)?;
+        queued.check_executable(e.ledger().timestamp())?;
+        queued.status = ChangeStatus::Executed;
+        write_change(&e, change_id, &queued);
+
+        match queued.change.clone() {
+            ParamChange::Upgrade(new_wasm_hash) => e.deployer().update_current_contract_wasm(new_wasm_hash),
+            ParamChange::InsuranceAddress(insurance) => put_insurance_address(&e, insurance),
+            ParamChange::TimelockDelay(delay) => put_timelock_delay(&e, delay),
+            ParamChange::PoolConfig(config) => write_config(&e, &config),
+            ParamChange::LockupTerms(lockup_period, early_exit_fee_bps) => {
+                put_lockup_terms(&e, lockup_period, early_exit_fee_bps)
+            }
+            ParamChange::TrancheTerms(senior_apr_bps, min_junior_coverage_bps) => {
+                // Seniors keep what they are owed at the old rate
+                checkpoint_senior_index(&e, get_senior_rate(&e));
+                put_senior_rate(&e, senior_apr_bps);
+                put_min_junior_coverage(&e, min_junior_coverage_bps);
+            }
+            ParamChange::EndorsementTerms(threshold, quorum) => put_endorsement_terms(&e, threshold, quorum),
+            ParamChange::DefaultWindow(window) => put_default_window(&e, window),
+        }
+
+        e.events()
+            .publish((AdminDataKey::CHANGE(change_id), Symbol::new(&e, "change_executed"), queued.status), queued);
+        Ok(())
+    }
+
+    pub fn cancel_change(e: Env, change_id: u64) -> Result<(), Error> {
+        check_deployed(&e)?;
+        read_administrator(&e)?.require_auth();
+
+        let mut queued = read_change(&e, change_id)?;
+        if queued.status != ChangeStatus::Queued {
+            return Err(Error::ChangeNotQueued);
+        }
+        queued.status = ChangeStatus::Cancelled;
+        write_change(&e, change_id, &queued);
+
+        e.events()
+            .publish((AdminDataKey::CHANGE(change_id), Symbol::new(&e, "change_cancelled"), queued.status), queued);
+        Ok(())
+    }
+
+    pub fn get_queued_change(e: Env, change_id: u64) -> Result<QueuedChange, Error> {
+        check_deployed(&e)?;
+        read_change(&e, change_id)
+    }
+
+    pub fn get_timelock_delay(e: Env) -> Result<u64, Error> {
+        check_deployed(&e)?;
+        Ok(get_timelock_delay(&e))
+    }
+
+    // Convert storage written by an earlier version to the current layout.
+    // Loans are converted in batches, returns true once the migration is done
+    // and false when it has to be called again.
+    pub fn migrate(e: Env) -> Result<bool, Error> {
+        check_deployed(&e)?;
+        read_administrator(&e)?.require_auth();
+
+        let from = get_schema_version(&e);
+        if from >= SCHEMA_VERSION {
+            return Err(Error::AlreadyMigrated);
+        }
+        if from == 0 {
+            return Err(Error::SchemaNotSupported);
+        }
+        if !migrate_loans(&e, from) {
+            return Ok(false);
+        }
+        migrate_settings(&e, from);
+        put_schema_version(&e, SCHEMA_VERSION);
+
+        e.events().publish((Symbol::new(&e, "migrated"), from), SCHEMA_VERSION);
+        Ok(true)
+    }
+
+    pub fn get_schema_version(e: Env) -> Result<u32, Error> {
+        check_deployed(&e)?;
+        Ok(get_schema_version(&e))
     }
 
     // Whitelist borrower's address after their to be able to request financing
-    pub fn whitelist(e: Env, address: Address) {
-        read_administrator(&e).require_auth();
-        e.storage().instance().set(&AdminDataKey::WHITELISTED(address), &true);
+    pub fn whitelist(e: Env, operator: Address, address: Address) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::KycOperator, &operator)?;
+        write_whitelisted(&e, &address);
+        Ok(())
     }
 
-    pub fn request_loan(e: Env, who: Address, invoice_amount: i128, inv_no: u32, repayment_date: u64) {
+    // Maintenance bump keeping loans, their invoice references and borrowers' whitelist entries alive
+    pub fn bump_loans(e: Env, loan_ids: Vec<u64>) -> Result<(), Error> {
+        check_initialized(&e)?;
+        read_administrator(&e)?.require_auth();
+
+        for loan_id in loan_ids.iter() {
+            let loan = bump_loan(&e, loan_id)?;
+            is_whitelisted(&e, &loan.who);
+        }
+        Ok(())
+    }
+
+    // Returns the loan id assigned to the borrower's invoice
+    pub fn request_loan(e: Env, who: Address, invoice_amount: i128, inv_no: u32, repayment_date: u64) -> Result<u64, Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::RequestLoan)?;
+        check_amount(invoice_amount)?;
+        if repayment_date <= e.ledger().timestamp() {
+            return Err(Error::InvalidRepaymentDate);
+        }
+        let config = read_config(&e);
+        let tenor = repayment_date - e.ledger().timestamp();
+        if tenor < config.min_tenor || tenor > config.max_tenor {
+            return Err(Error::TenorOutOfRange);
+        }
         // Borrower needs to authorize the loan request
         who.require_auth();
         // Check if the borrower is whitelisted
-        let whitelist: bool = e.storage().instance().get(&AdminDataKey::WHITELISTED(who.clone())).unwrap();
-        if !whitelist {
-            panic!("borrower is not whitelisted");
+        if !is_whitelisted(&e, &who) {
+            return Err(Error::NotWhitelisted);
+        }
+        // Each invoice can only be financed once
+        if read_loan_id(&e, &who, inv_no).is_some() {
+            return Err(Error::DuplicateInvoice);
         }
 
+        let loan_id = register_loan_id(&e, &who, inv_no);
         let loan = LoanDetails {
             who,
-            fee_rate: 0,
+            inv_no,
+            apr_bps: 0,
             invoice_amount,
             loan_amount: 0,
             repayment_date,
-            approved: false,
-            released: false,
-            repaid: false,
+            released_at: 0,
+            status: LoanStatus::Requested,
+            grace_period: 0,
+            penalty_apr_bps: 0,
+            installments: vec![&e, Installment { due_date: repayment_date, amount: invoice_amount }],
+            amount_repaid: 0,
+            penalty_paid: 0,
+            fee_paid: 0,
+            penalty_accrued: 0,
+            penalty_accrued_at: 0,
+            endorsements: Vec::new(&e),
+            insurance_fee_bps: 0,
+            protocol_fee_bps: 0,
         };
 
-        e.storage().instance().set(&AdminDataKey::INVNO(inv_no), &loan);
+        write_loan(&e, loan_id, &loan);
 
         e.events()
-            .publish((AdminDataKey::INVNO(inv_no), Symbol::new(&e, "loan_request")), loan);
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_request"), loan.status), loan);
+        Ok(loan_id)
     }
 
-    pub fn approve_loan(e: Env, inv_no: u32, fee_rate: i128) {
-        let admin = read_administrator(&e);
-        admin.require_auth();
+    // Price the loan at an APR in basis points, the advance is fixed when the loan is claimed
+    pub fn approve_loan(e: Env, operator: Address, loan_id: u64, apr_bps: i128) -> Result<(), Error> {
+        approve(&e, operator, loan_id, apr_bps, None)
+    }
 
-        if fee_rate > 100 {
-            panic!("fee rate cannot be more than 100%");
+    // Approve a loan repaid in installments rather than in one go on the repayment date
+    pub fn approve_loan_with_schedule(
+        e: Env,
+        operator: Address,
+        loan_id: u64,
+        apr_bps: i128,
+        installments: Vec<Installment>,
+    ) -> Result<(), Error> {
+        approve(&e, operator, loan_id, apr_bps, Some(installments))
+    }
+
+    // Sign off on the terms an underwriter approved, a loan above the
+    // endorsement threshold is approved once the quorum is reached
+    pub fn endorse_loan(e: Env, operator: Address, loan_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::ApproveLoan)?;
+        check_role(&e, Role::Underwriter, &operator)?;
+
+        let mut loan = read_loan(&e, loan_id)?;
+        loan.status.check_transition(LoanStatus::Approved)?;
+        if loan.endorsements.is_empty() {
+            return Err(Error::TermsNotProposed);
+        }
+        if loan.endorsements.contains(&operator) {
+            return Err(Error::AlreadyEndorsed);
         }
+        loan.endorsements.push_back(operator);
+
+        approve_if_endorsed(&e, loan_id, &mut loan)
+    }
+
+    // Invoices above the threshold need the quorum of underwriters to endorse them
+    pub fn get_endorsement_terms(e: Env) -> Result<(i128, u32), Error> {
+        check_initialized(&e)?;
+        Ok((get_endorsement_threshold(&e), get_endorsement_quorum(&e)))
+    }
+
+    // Turn down a loan that has not been released yet
+    pub fn reject_loan(e: Env, operator: Address, loan_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::Underwriter, &operator)?;
 
-        let mut loan: LoanDetails = e.storage().instance().get(&AdminDataKey::INVNO(inv_no)).unwrap();
-        loan.approved = true;
-        loan.fee_rate = fee_rate;
-        loan.loan_amount = loan.invoice_amount * (100 - fee_rate) / 100;
+        let mut loan = read_loan(&e, loan_id)?;
+        loan.transition(LoanStatus::Rejected)?;
+        write_loan(&e, loan_id, &loan);
 
-        e.storage().instance().set(&AdminDataKey::INVNO(inv_no), &loan);
+        e.events()
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_rejected"), loan.status), loan);
+        Ok(())
+    }
+
+    // Borrower withdraws a loan request before claiming the funds
+    pub fn cancel_loan(e: Env, loan_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        let mut loan = read_loan(&e, loan_id)?;
+        loan.who.require_auth();
+
+        loan.transition(LoanStatus::Cancelled)?;
+        write_loan(&e, loan_id, &loan);
 
         e.events()
-            .publish((AdminDataKey::INVNO(inv_no), Symbol::new(&e, "loan_approved")), loan);
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_cancelled"), loan.status), loan);
+        Ok(())
     }
 
-    pub fn claim_loan(e: Env, inv_no: u32) {
-        let mut loan: LoanDetails = e.storage().instance().get(&AdminDataKey::INVNO(inv_no)).unwrap();
+    pub fn claim_loan(e: Env, loan_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_not_paused(&e, Operation::ClaimLoan)?;
+        let mut loan = read_loan(&e, loan_id)?;
         loan.who.require_auth();
-        // Must be approved
-        if !loan.approved {
-            panic!("loan not approved");
+        // Must be approved and not yet released
+        loan.transition(LoanStatus::Released)?;
+        if e.ledger().timestamp() >= loan.repayment_date {
+            return Err(Error::InvalidRepaymentDate);
+        }
+
+        // Discount the invoice for the days left until repayment, queued
+        // withdrawals are funded before any new lending
+        loan.price(e.ledger().timestamp());
+        check_advance(&e, &loan)?;
+        fund_withdrawals(&e);
+        if loan.loan_amount > get_idle_assets(&e) {
+            return Err(Error::InsufficientLiquidity);
         }
+        // No new lending while juniors are too thin to protect seniors
+        check_junior_coverage(&e, 0)?;
 
         // Release fund to the borrower
-        transfer(&e, get_usdc(&e), loan.who.clone(), loan.loan_amount.clone());
-        loan.released = true;
+        transfer(&e, get_usdc(&e), loan.who.clone(), loan.loan_amount);
+        put_idle_assets(&e, get_idle_assets(&e) - loan.loan_amount);
+        loan.released_at = e.ledger().timestamp();
 
         // Update loan details and total loan amount
-        e.storage().instance().set(&AdminDataKey::INVNO(inv_no), &loan);
+        write_loan(&e, loan_id, &loan);
         put_total_loan_amount(&e, get_total_loan_amount(&e) + loan.loan_amount);
         put_total_outstanding_loan(&e, get_total_outstanding_loan(&e) + loan.loan_amount);
 
+        // Start accruing the loan's fee into NAV
+        accrue_fees(&e);
+        if loan_accrual_rate(&loan) == 0 {
+            put_accrued_fees(&e, get_accrued_fees(&e) + lp_fee(&loan));
+        } else {
+            start_fee_accrual(&e, &loan);
+        }
+        put_outstanding_fees(&e, get_outstanding_fees(&e) + lp_fee(&loan));
+
+        e.events()
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_released"), loan.status), loan);
+        Ok(())
+    }
+
+    // Flag a released loan with an installment past due, callable by anyone
+    pub fn mark_overdue(e: Env, loan_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        let mut loan = read_loan(&e, loan_id)?;
+        match loan.next_due_date() {
+            Some(due_date) if e.ledger().timestamp() > due_date => {}
+            _ => return Err(Error::NotOverdue),
+        }
+
+        loan.transition(LoanStatus::Overdue)?;
+        write_loan(&e, loan_id, &loan);
+
         e.events()
-            .publish((AdminDataKey::INVNO(inv_no), Symbol::new(&e, "loan_released")), loan);
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_overdue"), loan.status), loan);
+        Ok(())
     }
 
-    // Returns amount repaid and fees to insurance
-    pub fn repay_loan(e: Env, inv_no: u32) -> (i128, i128) {
-        let mut loan: LoanDetails = e.storage().instance().get(&AdminDataKey::INVNO(inv_no)).unwrap();
+    // Returns amount repaid, including any late penalty, and fees to insurance
+    pub fn repay_loan(e: Env, loan_id: u64) -> Result<(i128, i128), Error> {
+        check_initialized(&e)?;
+        let mut loan = read_loan(&e, loan_id)?;
         loan.who.require_auth();
         // Must be released loan and repayment date reached
-        if !loan.released && loan.repayment_date < e.ledger().timestamp() {
-            panic!("loan not released or repayment date not reached");
+        loan.status.check_transition(LoanStatus::Repaid)?;
+        if e.ledger().timestamp() < loan.repayment_date {
+            return Err(Error::RepaymentDateNotReached);
         }
 
-        // Repay the loan
-        let usdc_client = token::Client::new(&e, &get_usdc(&e));
-        usdc_client.transfer(&loan.who, &e.current_contract_address(), &loan.invoice_amount);
+        let amount_due = loan.amount_due(e.ledger().timestamp());
+        let fees_to_insurance = collect_repayment(&e, loan_id, &mut loan, amount_due)?;
+        Ok((amount_due, fees_to_insurance))
+    }
 
-        // Update loan details and total loan amount
-        loan.repaid = true;
-        e.storage().instance().set(&AdminDataKey::INVNO(inv_no), &loan);
+    // Repay part of what is due, applied to penalties, then fees, then principal.
+    // Anything above the amount due is not collected. Returns amount repaid and
+    // fees to insurance.
+    pub fn repay_partial(e: Env, loan_id: u64, amount: i128) -> Result<(i128, i128), Error> {
+        check_initialized(&e)?;
+        check_amount(amount)?;
+        let mut loan = read_loan(&e, loan_id)?;
+        loan.who.require_auth();
+        loan.status.check_transition(LoanStatus::Repaid)?;
+
+        let amount = amount.min(loan.amount_due(e.ledger().timestamp()));
+        let fees_to_insurance = collect_repayment(&e, loan_id, &mut loan, amount)?;
+        Ok((amount, fees_to_insurance))
+    }
+
+    // Default a loan whose oldest unpaid installment is past due by more than
+    // the default window. Its fee stops accruing and the principal left is
+    // fully provisioned until it is written off.
+    pub fn mark_default(e: Env, operator: Address, loan_id: u64) -> Result<(), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::RiskManager, &operator)?;
+
+        let mut loan = read_loan(&e, loan_id)?;
+        match loan.next_due_date() {
+            Some(due_date) if e.ledger().timestamp() > due_date.saturating_add(get_default_window(&e)) => {}
+            _ => return Err(Error::DefaultWindowNotElapsed),
+        }
+        if loan.status == LoanStatus::Released {
+            loan.transition(LoanStatus::Overdue)?;
+        }
+        loan.transition(LoanStatus::Defaulted)?;
+
+        stop_fee_accrual(&e, &loan);
+        put_impairments(&e, get_impairments(&e) + loan.principal_outstanding());
+        write_loan(&e, loan_id, &loan);
+
+        e.events()
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_defaulted"), loan.status), loan);
+        Ok(())
+    }
+
+    // Close a defaulted loan after collections. `recovered_amount` is paid in by
+    // the operator, the loss left is drawn from the insurance reserve first and
+    // the rest is borne by LPs. Returns the insurance cover and the LP loss.
+    pub fn write_off(e: Env, operator: Address, loan_id: u64, recovered_amount: i128) -> Result<(i128, i128), Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::RiskManager, &operator)?;
+        if recovered_amount < 0 {
+            return Err(Error::InvalidAmount);
+        }
+
+        let mut loan = read_loan(&e, loan_id)?;
+        loan.transition(LoanStatus::WrittenOff)?;
+        let principal = loan.principal_outstanding();
+
+        if recovered_amount > 0 {
+            let usdc_client = token::Client::new(&e, &get_usdc(&e));
+            usdc_client.transfer(&operator, &e.current_contract_address(), &recovered_amount);
+            put_idle_assets(&e, get_idle_assets(&e) + recovered_amount);
+            loan.amount_repaid += recovered_amount;
+        }
+
+        // Release the provision and take the principal off the book
+        put_impairments(&e, get_impairments(&e) - principal);
+        put_total_outstanding_loan(&e, get_total_outstanding_loan(&e) - principal);
+        write_loan(&e, loan_id, &loan);
+
+        e.events()
+            .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "loan_written_off"), loan.status), loan);
+
+        // Loss waterfall: insurance reserve first, then juniors and only then
+        // seniors through their share of NAV
+        let loss = (principal - recovered_amount).max(0);
+        let covered = draw_insurance(&e, loan_id, loss);
+        if covered > 0 {
+            e.events()
+                .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "insurance_cover")), covered);
+        }
+        let lp_loss = loss - covered;
+        if lp_loss > 0 {
+            let nav_before_loss = get_nav(&e).max(0) + lp_loss;
+            let junior_loss = lp_loss.min(nav_before_loss - senior_claim(&e).min(nav_before_loss));
+            e.events()
+                .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "junior_loss")), junior_loss);
+            if lp_loss > junior_loss {
+                e.events()
+                    .publish((AdminDataKey::LOAN(loan_id), Symbol::new(&e, "senior_loss")), lp_loss - junior_loss);
+            }
+        }
+        Ok((covered, lp_loss))
+    }
+
+    pub fn get_default_window(e: Env) -> Result<u64, Error> {
+        check_initialized(&e)?;
+        Ok(get_default_window(&e))
+    }
+
+    // Send USDC the pool does not track to `to`, returns the amount skimmed
+    pub fn skim(e: Env, operator: Address, to: Address) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::Treasurer, &operator)?;
+
+        let surplus = balance_check(&e).surplus;
+        if surplus > 0 {
+            transfer(&e, get_usdc(&e), to.clone(), surplus);
+            e.events().publish((Symbol::new(&e, "skim"), to), surplus);
+        }
+        Ok(surplus.max(0))
+    }
 
-        // Update total outstanding loan amount
-        put_total_outstanding_loan(&e, get_total_outstanding_loan(&e) - loan.loan_amount);
+    // Pay out the protocol's cut of fees collected so far
+    pub fn collect_protocol_fees(e: Env, operator: Address, to: Address) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_role(&e, Role::Treasurer, &operator)?;
+
+        let amount = get_protocol_fees(&e);
+        if amount > 0 {
+            put_protocol_fees(&e, 0);
+            transfer(&e, get_usdc(&e), to.clone(), amount);
+            e.events().publish((Symbol::new(&e, "protocol_fees"), to), amount);
+        }
+        Ok(amount)
+    }
+
+    // Invariant: the USDC held covers idle assets, the withdrawal reserve and
+    // the protocol fees not yet collected
+    pub fn check_balances(e: Env) -> Result<BalanceCheck, Error> {
+        check_initialized(&e)?;
+        Ok(balance_check(&e))
+    }
+
+    // USDC the pool can lend or pay out
+    pub fn get_idle_assets(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_idle_assets(&e))
+    }
+
+    // Principal provisioned against defaulted loans not yet written off
+    pub fn get_impairments(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_impairments(&e))
+    }
+
+    // Lockup after each deposit and, if early exits are allowed, their fee in
+    // basis points. Without a fee withdrawals are blocked until the lockup ends.
+    pub fn get_lockup_terms(e: Env) -> Result<(u64, Option<i128>), Error> {
+        check_initialized(&e)?;
+        Ok((get_lockup_period(&e), get_early_exit_fee(&e)))
+    }
+
+    // When the last of the holder's locked shares in a tranche can be
+    // withdrawn without a fee, 0 when none are locked
+    pub fn get_lockup_end(e: Env, who: Address, tranche: Tranche) -> Result<u64, Error> {
+        check_initialized(&e)?;
+        Ok(read_lockups(&e, &who, tranche).last().map_or(0, |lockup| lockup.unlocks_at))
+    }
+
+    // The holder's lockups in a tranche, soonest to unlock first
+    pub fn get_lockups(e: Env, who: Address, tranche: Tranche) -> Result<Vec<Lockup>, Error> {
+        check_initialized(&e)?;
+        Ok(read_lockups(&e, &who, tranche))
+    }
+
+    // The holder's shares in a tranche the pool holds until their lockup ends
+    pub fn get_locked_shares(e: Env, who: Address, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(total_locked(&read_lockups(&e, &who, tranche)))
+    }
+
+    // Hand the holder their shares once the lockup is over, callable by anyone.
+    // Returns the shares released.
+    pub fn unlock_shares(e: Env, who: Address, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(release_unlocked(&e, &who, tranche))
+    }
+
+    // Senior APR cap and the minimum junior share of NAV, both in basis points
+    pub fn get_tranche_terms(e: Env) -> Result<(i128, i128), Error> {
+        check_initialized(&e)?;
+        Ok((get_senior_rate(&e), get_min_junior_coverage(&e)))
+    }
+
+    // Junior NAV as a share of the pool's NAV, in basis points
+    pub fn get_junior_coverage(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(junior_coverage(&e))
+    }
+
+    // Grace period and late penalty APR for loans approved from now on. Both
+    // are part of the pool config and only change through the timelock.
+    pub fn get_late_payment_terms(e: Env) -> Result<(u64, i128), Error> {
+        check_initialized(&e)?;
+        Ok((get_grace_period(&e), get_penalty_rate(&e)))
+    }
+
+    // Invoice amount still owed plus any unpaid late penalty
+    pub fn get_amount_due(e: Env, loan_id: u64) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        let loan = read_loan(&e, loan_id)?;
+        Ok(loan.amount_due(e.ledger().timestamp()))
+    }
+
+    pub fn get_usdc_address(e: Env) -> Result<Address, Error> {
+        check_initialized(&e)?;
+        Ok(get_usdc(&e))
+    }
+
+    pub fn share_id(e: Env, tranche: Tranche) -> Result<Address, Error> {
+        check_initialized(&e)?;
+        Ok(get_token_share(&e, tranche))
+    }
+
+    pub fn get_shares(e: Env, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_total_shares(&e, tranche))
+    }
+
+    pub fn get_user_share_balance(e: Env, who: Address, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        let share_amount = get_balance_shares_of(&e, tranche, &who) + total_locked(&read_lockups(&e, &who, tranche));
+        Ok(assets_for_shares(&e, tranche, share_amount))
+    }
+
+    // Net asset value of the pool, including loans lent out and their accrued fees
+    pub fn get_nav(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_nav(&e))
+    }
+
+    // Vault views per tranche, amounts are in USDC and tranche share base units
+    pub fn total_assets(e: Env, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_tranche_nav(&e, tranche))
+    }
+
+    pub fn convert_to_shares(e: Env, tranche: Tranche, amount: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(shares_for_assets(&e, tranche, amount))
+    }
+
+    pub fn convert_to_assets(e: Env, tranche: Tranche, share_amount: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(assets_for_shares(&e, tranche, share_amount))
+    }
+
+    // Nothing while deposits are paused
+    pub fn max_deposit(e: Env, _who: Address, _tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        if check_not_paused(&e, Operation::Deposit).is_err() {
+            return Ok(0);
+        }
+        Ok(i128::MAX)
+    }
+
+    // Bounded by the holder's shares net of any early exit fee, by the USDC
+    // not lent out and for juniors by the minimum coverage. Nothing while
+    // withdrawals are paused or queued, locked shares only count if they can
+    // exit early.
+    pub fn max_withdraw(e: Env, who: Address, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        if check_not_paused(&e, Operation::Withdraw).is_err() || has_pending_withdrawals(&e, tranche) {
+            return Ok(0);
+        }
+        let mut held = get_balance_shares_of(&e, tranche, &who);
+        let mut locked = 0;
+        for lockup in read_lockups(&e, &who, tranche).iter() {
+            if lockup.is_expired(e.ledger().timestamp()) {
+                held += lockup.share_amount;
+            } else {
+                locked += lockup.share_amount;
+            }
+        }
+        let mut fee = 0;
+        if let Some(exit_fee_bps) = get_early_exit_fee(&e) {
+            held += locked;
+            fee = exit_fee(assets_for_shares(&e, tranche, locked), exit_fee_bps);
+        }
+        let max = (assets_for_shares(&e, tranche, held) - fee).min(get_idle_assets(&e));
+        match tranche {
+            Tranche::Senior => Ok(max),
+            Tranche::Junior => Ok(max.min(junior_withdrawable(&e))),
+        }
+    }
+
+    pub fn preview_deposit(e: Env, tranche: Tranche, amount: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(shares_for_assets(&e, tranche, amount))
+    }
+
+    pub fn preview_mint(e: Env, tranche: Tranche, share_amount: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(assets_for_shares_up(&e, tranche, share_amount))
+    }
+
+    pub fn preview_withdraw(e: Env, tranche: Tranche, amount: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(shares_for_assets_up(&e, tranche, amount))
+    }
+
+    pub fn preview_redeem(e: Env, tranche: Tranche, share_amount: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(assets_for_shares(&e, tranche, share_amount))
+    }
 
-        // Update fees earned and transfer portion to insurance
-        let fees_earned = get_fees_earned(&e);
-        let fee = (loan.invoice_amount - loan.loan_amount) * (100 - FEES_PORTION_FOR_INSURANCE) / 100;
-        put_fees_earned(&e, fees_earned + fee);
-        let fees_to_insurance = (loan.invoice_amount - loan.loan_amount) - fee;
-        usdc_client.transfer(&e.current_contract_address(), &get_insurance_address(&e), &fees_to_insurance);
-        (loan.invoice_amount, fees_to_insurance)
+    // USDC value of one whole tranche share (SHARE_PRICE_SCALE units)
+    pub fn get_share_price(e: Env, tranche: Tranche) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        let (total_shares, nav) = share_price_basis(&e, tranche);
+        Ok(nav * SHARE_PRICE_SCALE / total_shares)
     }
 
-    pub fn get_usdc_address(e: Env) -> Address {
-        get_usdc(&e)
+    pub fn get_loan_details(e: Env, loan_id: u64) -> Result<LoanDetails, Error> {
+        check_initialized(&e)?;
+        read_loan(&e, loan_id)
     }
 
-    pub fn share_id(e: Env) -> Address {
-        get_token_share(&e)
+    // Discount charged on a hypothetical invoice financed for `tenor_days` at `apr_bps`
+    pub fn quote_discount(e: Env, invoice_amount: i128, tenor_days: u32, apr_bps: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        check_amount(invoice_amount)?;
+        check_apr(&e, apr_bps)?;
+        Ok(discount(invoice_amount, apr_bps, tenor_days as i128))
     }
 
-    pub fn get_shares(e: Env) -> i128 {
-        get_total_shares(&e)
+    // Look up the loan id from the borrower's own invoice reference
+    pub fn get_loan_id(e: Env, who: Address, inv_no: u32) -> Result<u64, Error> {
+        check_initialized(&e)?;
+        read_loan_id(&e, &who, inv_no).ok_or(Error::LoanNotFound)
     }
 
-    pub fn get_user_share_balance(e: Env, who: Address) -> i128 {
-        let balance = token::Client::new(&e, &get_token_share(&e)).balance(&who);
-        balance * get_balance_usdc(&e) / get_total_shares(&e)
+    // Insurance cut of loan fees in basis points
+    pub fn get_insurance_fee_rate(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(read_config(&e).insurance_fee_bps)
     }
 
-    pub fn get_loan_details(e: Env, inv_no: u32) -> LoanDetails {
-        e.storage().instance().get(&AdminDataKey::INVNO(inv_no)).unwrap()
+    pub fn get_config(e: Env) -> Result<PoolConfig, Error> {
+        check_initialized(&e)?;
+        Ok(read_config(&e))
     }
 
-    pub fn get_insurance_fee_rate(e: Env) -> i128 {
-        FEES_PORTION_FOR_INSURANCE
+    pub fn get_protocol_fees(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_protocol_fees(&e))
     }
 
-    pub fn get_insurance_address(e: Env) -> Address {
-        get_insurance_address(&e)
+    pub fn get_insurance_address(e: Env) -> Result<Address, Error> {
+        check_initialized(&e)?;
+        Ok(get_insurance_address(&e))
     }
 
-    pub fn get_fees_earned(e: Env) -> i128 {
-        get_fees_earned(&e)
+    pub fn get_fees_earned(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_fees_earned(&e))
     }
 }
@@ -0,0 +1,281 @@
+use soroban_sdk::{contracttype, Address, Env, Vec};
+
+use crate::{
+    div_ceil, AdminDataKey, DataKey, Error, BPS_DENOMINATOR, DAYS_PER_YEAR, LOAN_BUMP_AMOUNT,
+    LOAN_LIFETIME_THRESHOLD, SECONDS_PER_DAY, SECONDS_PER_YEAR,
+};
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum LoanStatus {
+    Requested = 0,
+    Approved = 1,
+    Rejected = 2,
+    Cancelled = 3,
+    Released = 4,
+    Repaid = 5,
+    Overdue = 6,
+    Defaulted = 7,
+    WrittenOff = 8,
+}
+
+impl LoanStatus {
+    pub fn can_transition_to(self, next: LoanStatus) -> bool {
+        use LoanStatus::*;
+        matches!(
+            (self, next),
+            (Requested, Approved)
+                | (Requested, Rejected)
+                | (Requested, Cancelled)
+                | (Approved, Rejected)
+                | (Approved, Cancelled)
+                | (Approved, Released)
+                | (Released, Repaid)
+                | (Released, Overdue)
+                | (Overdue, Repaid)
+                | (Overdue, Defaulted)
+                | (Defaulted, WrittenOff)
+        )
+    }
+
+    // Error explaining why a loan in this status cannot move to `next`
+    pub fn check_transition(self, next: LoanStatus) -> Result<(), Error> {
+        use LoanStatus::*;
+        if self.can_transition_to(next) {
+            return Ok(());
+        }
+        Err(match (self, next) {
+            (Approved, Approved) => Error::AlreadyApproved,
+            (Requested, Released) => Error::NotApproved,
+            (Released | Overdue, Released) => Error::AlreadyReleased,
+            (Requested | Approved, Repaid) => Error::NotReleased,
+            (Repaid, _) => Error::AlreadyRepaid,
+            _ => Error::InvalidStatusTransition,
+        })
+    }
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Installment {
+    pub due_date: u64,
+    pub amount: i128,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoanDetails {
+    pub who: Address,
+    pub inv_no: u32,
+    pub apr_bps: i128,
+    pub invoice_amount: i128,
+    pub loan_amount: i128,
+    pub repayment_date: u64,
+    pub released_at: u64,
+    pub status: LoanStatus,
+    // Late payment terms fixed at approval
+    pub grace_period: u64,
+    pub penalty_apr_bps: i128,
+    // Invoice amount split by due date, a single installment on the repayment
+    // date unless approved with a schedule
+    pub installments: Vec<Installment>,
+    // Repayments so far and how they were applied
+    pub amount_repaid: i128,
+    pub penalty_paid: i128,
+    pub fee_paid: i128,
+    // Penalty charged up to `penalty_accrued_at`
+    pub penalty_accrued: i128,
+    pub penalty_accrued_at: u64,
+    // Underwriters who signed off on the terms, the first one proposed them
+    pub endorsements: Vec<Address>,
+    // Cuts of the fee and penalties, fixed at approval
+    pub insurance_fee_bps: i128,
+    pub protocol_fee_bps: i128,
+}
+
+impl LoanDetails {
+    // Set the advance for a loan released at `now`, each installment is
+    // discounted for its own tenor
+    pub fn price(&mut self, now: u64) {
+        let mut total_discount = 0;
+        for installment in self.installments.iter() {
+            let days = tenor_days(now, installment.due_date);
+            total_discount += discount(installment.amount, self.apr_bps, days);
+        }
+        self.loan_amount = self.invoice_amount - total_discount;
+    }
+
+    // Fee charged for the advance
+    pub fn fee(&self) -> i128 {
+        self.invoice_amount - self.loan_amount
+    }
+
+    // Insurance cut of fees or penalties paid, rounded down so any dust stays
+    // with LPs
+    pub fn insurance_cut(&self, amount: i128) -> i128 {
+        amount * self.insurance_fee_bps / BPS_DENOMINATOR
+    }
+
+    pub fn protocol_cut(&self, amount: i128) -> i128 {
+        amount * self.protocol_fee_bps / BPS_DENOMINATOR
+    }
+
+    // What LPs keep of fees or penalties paid once both cuts are taken
+    pub fn lp_share(&self, amount: i128) -> i128 {
+        amount - self.insurance_cut(amount) - self.protocol_cut(amount)
+    }
+
+    // Fee and principal still owed, excluding penalties
+    pub fn balance(&self) -> i128 {
+        self.invoice_amount - (self.amount_repaid - self.penalty_paid)
+    }
+
+    // Advance not yet paid back
+    pub fn principal_outstanding(&self) -> i128 {
+        self.loan_amount - (self.amount_repaid - self.penalty_paid - self.fee_paid)
+    }
+
+    // Due date of the first installment not yet covered by repayments
+    pub fn next_due_date(&self) -> Option<u64> {
+        let mut paid = self.amount_repaid - self.penalty_paid;
+        for installment in self.installments.iter() {
+            if paid < installment.amount {
+                return Some(installment.due_date);
+            }
+            paid -= installment.amount;
+        }
+        None
+    }
+
+    // Charge penalty interest up to `now` on the unpaid part of every
+    // installment past its due date and grace period, rounded up in favour
+    // of the pool
+    pub fn accrue_penalty(&mut self, now: u64) {
+        if now <= self.penalty_accrued_at {
+            return;
+        }
+        let mut paid = self.amount_repaid - self.penalty_paid;
+        let mut late_amount_seconds = 0;
+        for installment in self.installments.iter() {
+            let covered = paid.min(installment.amount);
+            paid -= covered;
+            let late_from = self
+                .penalty_accrued_at
+                .max(installment.due_date.saturating_add(self.grace_period));
+            if now > late_from {
+                late_amount_seconds += (installment.amount - covered) * (now - late_from) as i128;
+            }
+        }
+        self.penalty_accrued += div_ceil(
+            late_amount_seconds * self.penalty_apr_bps,
+            SECONDS_PER_YEAR * BPS_DENOMINATOR,
+        );
+        self.penalty_accrued_at = now;
+    }
+
+    // Unpaid penalty at `now`
+    pub fn penalty(&self, now: u64) -> i128 {
+        let mut loan = self.clone();
+        loan.accrue_penalty(now);
+        loan.penalty_accrued - loan.penalty_paid
+    }
+
+    // What the borrower owes at `now`, nothing until the funds are released
+    pub fn amount_due(&self, now: u64) -> i128 {
+        match self.status {
+            LoanStatus::Released | LoanStatus::Overdue => self.balance() + self.penalty(now),
+            _ => 0,
+        }
+    }
+
+    // Apply a repayment to penalties, then fees, then principal. Returns how
+    // much went to each.
+    pub fn apply_payment(&mut self, now: u64, amount: i128) -> (i128, i128, i128) {
+        self.accrue_penalty(now);
+        let penalty = amount.min(self.penalty_accrued - self.penalty_paid);
+        let fee = (amount - penalty).min(self.fee() - self.fee_paid);
+        let principal = amount - penalty - fee;
+
+        self.penalty_paid += penalty;
+        self.fee_paid += fee;
+        self.amount_repaid += amount;
+        (penalty, fee, principal)
+    }
+
+    // Move the loan to `next`, rejecting transitions the state machine does not allow
+    pub fn transition(&mut self, next: LoanStatus) -> Result<(), Error> {
+        self.status.check_transition(next)?;
+        self.status = next;
+        Ok(())
+    }
+}
+
+// Installments must be positive, in date order, add up to the invoice and end
+// on the repayment date
+pub fn check_schedule(loan: &LoanDetails, installments: &Vec<Installment>, now: u64) -> Result<(), Error> {
+    let mut total = 0;
+    let mut last_due_date = now;
+    for installment in installments.iter() {
+        if installment.amount <= 0 || installment.due_date <= last_due_date {
+            return Err(Error::InvalidSchedule);
+        }
+        total += installment.amount;
+        last_due_date = installment.due_date;
+    }
+    if total != loan.invoice_amount || last_due_date != loan.repayment_date {
+        return Err(Error::InvalidSchedule);
+    }
+    Ok(())
+}
+
+// Days financed until `repayment_date`, a part day counts as a whole one
+pub fn tenor_days(from: u64, repayment_date: u64) -> i128 {
+    repayment_date.saturating_sub(from).div_ceil(SECONDS_PER_DAY) as i128
+}
+
+// Discount on an invoice financed for `days` at `apr_bps`, rounded up in favour of the pool
+pub fn discount(invoice_amount: i128, apr_bps: i128, days: i128) -> i128 {
+    div_ceil(invoice_amount * apr_bps * days, DAYS_PER_YEAR * BPS_DENOMINATOR)
+}
+
+pub fn read_loan(e: &Env, loan_id: u64) -> Result<LoanDetails, Error> {
+    let key = AdminDataKey::LOAN(loan_id);
+    let loan = e.storage().persistent().get(&key).ok_or(Error::LoanNotFound)?;
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    Ok(loan)
+}
+
+pub fn write_loan(e: &Env, loan_id: u64, loan: &LoanDetails) {
+    let key = AdminDataKey::LOAN(loan_id);
+    e.storage().persistent().set(&key, loan);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+// Internal loan id registered for a borrower's own invoice reference
+pub fn read_loan_id(e: &Env, who: &Address, inv_no: u32) -> Option<u64> {
+    let key = AdminDataKey::INVREF(who.clone(), inv_no);
+    let loan_id = e.storage().persistent().get(&key);
+    if loan_id.is_some() {
+        e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    }
+    loan_id
+}
+
+// Assign the next monotonic loan id to a borrower's invoice reference
+pub fn register_loan_id(e: &Env, who: &Address, inv_no: u32) -> u64 {
+    let loan_id: u64 = e.storage().instance().get(&DataKey::NextLoanId).unwrap_or(1);
+    e.storage().instance().set(&DataKey::NextLoanId, &(loan_id + 1));
+
+    let key = AdminDataKey::INVREF(who.clone(), inv_no);
+    e.storage().persistent().set(&key, &loan_id);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    loan_id
+}
+
+// Extend the TTL of a loan and of its invoice reference
+pub fn bump_loan(e: &Env, loan_id: u64) -> Result<LoanDetails, Error> {
+    let loan = read_loan(e, loan_id)?;
+    read_loan_id(e, &loan.who, loan.inv_no);
+    Ok(loan)
+}
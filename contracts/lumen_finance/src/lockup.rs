@@ -0,0 +1,62 @@
+use soroban_sdk::{contracttype, Address, Env, Vec};
+
+use crate::{AdminDataKey, Tranche, LOAN_BUMP_AMOUNT, LOAN_LIFETIME_THRESHOLD};
+
+// Shares minted inside the lockup are held by the pool until `unlocks_at`, so
+// they cannot be moved to another address to skip the lockup. The unlock time
+// is fixed when the shares are deposited, neither a later deposit nor a later
+// change of the lockup terms moves it.
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lockup {
+    pub share_amount: i128,
+    pub unlocks_at: u64,
+}
+
+impl Lockup {
+    pub fn is_expired(&self, now: u64) -> bool {
+        now >= self.unlocks_at
+    }
+}
+
+// A holder's lockups in a tranche, one per unlock time, soonest first
+pub fn read_lockups(e: &Env, who: &Address, tranche: Tranche) -> Vec<Lockup> {
+    let key = AdminDataKey::LOCKED(who.clone(), tranche);
+    match e.storage().persistent().get(&key) {
+        Some(lockups) => {
+            e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+            lockups
+        }
+        None => Vec::new(e),
+    }
+}
+
+pub fn write_lockups(e: &Env, who: &Address, tranche: Tranche, lockups: &Vec<Lockup>) {
+    let key = AdminDataKey::LOCKED(who.clone(), tranche);
+    if lockups.is_empty() {
+        e.storage().persistent().remove(&key);
+        return;
+    }
+    e.storage().persistent().set(&key, lockups);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+pub fn total_locked(lockups: &Vec<Lockup>) -> i128 {
+    lockups.iter().map(|lockup| lockup.share_amount).sum()
+}
+
+// Insert `lockup` in unlock order, merged with shares unlocking at the same time
+pub fn add_lockup(lockups: &mut Vec<Lockup>, lockup: Lockup) {
+    for (index, mut existing) in lockups.iter().enumerate() {
+        if existing.unlocks_at == lockup.unlocks_at {
+            existing.share_amount += lockup.share_amount;
+            lockups.set(index as u32, existing);
+            return;
+        }
+        if existing.unlocks_at > lockup.unlocks_at {
+            lockups.insert(index as u32, lockup);
+            return;
+        }
+    }
+    lockups.push_back(lockup);
+}
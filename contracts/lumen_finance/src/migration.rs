@@ -0,0 +1,173 @@
+use soroban_sdk::{contracttype, Address, Env, Vec};
+
+use crate::{
+    config::{read_config, write_config},
+    loan::write_loan,
+    AdminDataKey, DataKey, Installment, LoanDetails, LoanStatus, INSURANCE_FEE_BPS,
+};
+
+// Storage layout this code reads and writes. Bump it with every layout change
+// and convert the older layout in `migrate_loan` or `migrate_settings`. Only
+// pools that stored a version can be migrated, earlier layouts are left to a
+// fresh deployment.
+//
+// 1: schema version stored, every setting written explicitly
+// 2: `LoanDetails` records the underwriters who endorsed the loan
+// 3: `PoolConfig` holds the late payment terms, loans fix their fee cuts at
+//    approval
+pub const SCHEMA_VERSION: u32 = 3;
+
+// Loans converted per `migrate` call, keeps each call within resource limits
+pub(crate) const MAX_LOANS_PER_MIGRATION: u64 = 50;
+
+// 0 for a pool initialized before the version was stored
+pub fn get_schema_version(e: &Env) -> u32 {
+    e.storage().instance().get(&DataKey::SchemaVersion).unwrap_or(0)
+}
+
+pub fn put_schema_version(e: &Env, version: u32) {
+    e.storage().instance().set(&DataKey::SchemaVersion, &version);
+}
+
+// Convert the next batch of loans written under schema `from`. Returns true
+// once every loan is on the current layout.
+pub fn migrate_loans(e: &Env, from: u32) -> bool {
+    let next_loan_id: u64 = e.storage().instance().get(&DataKey::NextLoanId).unwrap_or(1);
+    let start: u64 = e.storage().instance().get(&DataKey::MigrationCursor).unwrap_or(1);
+    let end = next_loan_id.min(start + MAX_LOANS_PER_MIGRATION);
+
+    for loan_id in start..end {
+        migrate_loan(e, from, loan_id);
+    }
+
+    if end < next_loan_id {
+        e.storage().instance().set(&DataKey::MigrationCursor, &end);
+        return false;
+    }
+    e.storage().instance().remove(&DataKey::MigrationCursor);
+    true
+}
+
+fn migrate_loan(e: &Env, from: u32, loan_id: u64) {
+    let key = AdminDataKey::LOAN(loan_id);
+    let loan = if from < 2 {
+        e.storage().persistent().get::<_, LoanDetailsV1>(&key).map(|loan| loan.migrate(e))
+    } else {
+        e.storage().persistent().get::<_, LoanDetailsV2>(&key)
+    };
+    if let Some(loan) = loan {
+        write_loan(e, loan_id, &loan.migrate());
+    }
+}
+
+pub fn migrate_settings(e: &Env, from: u32) {
+    if from < 3 {
+        let mut config = read_config(e);
+        if let Some(grace_period) = e.storage().instance().get(&DataKey::GracePeriod) {
+            config.grace_period = grace_period;
+            e.storage().instance().remove(&DataKey::GracePeriod);
+        }
+        if let Some(penalty_apr_bps) = e.storage().instance().get(&DataKey::PenaltyRate) {
+            config.penalty_apr_bps = penalty_apr_bps;
+            e.storage().instance().remove(&DataKey::PenaltyRate);
+        }
+        write_config(e, &config);
+    }
+}
+
+// `LoanDetails` up to version 1
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoanDetailsV1 {
+    pub who: Address,
+    pub inv_no: u32,
+    pub apr_bps: i128,
+    pub invoice_amount: i128,
+    pub loan_amount: i128,
+    pub repayment_date: u64,
+    pub released_at: u64,
+    pub status: LoanStatus,
+    pub grace_period: u64,
+    pub penalty_apr_bps: i128,
+    pub installments: Vec<Installment>,
+    pub amount_repaid: i128,
+    pub penalty_paid: i128,
+    pub fee_paid: i128,
+    pub penalty_accrued: i128,
+    pub penalty_accrued_at: u64,
+}
+
+impl LoanDetailsV1 {
+    // Loans approved before endorsements were recorded keep an empty list
+    pub fn migrate(self, e: &Env) -> LoanDetailsV2 {
+        LoanDetailsV2 {
+            who: self.who,
+            inv_no: self.inv_no,
+            apr_bps: self.apr_bps,
+            invoice_amount: self.invoice_amount,
+            loan_amount: self.loan_amount,
+            repayment_date: self.repayment_date,
+            released_at: self.released_at,
+            status: self.status,
+            grace_period: self.grace_period,
+            penalty_apr_bps: self.penalty_apr_bps,
+            installments: self.installments,
+            amount_repaid: self.amount_repaid,
+            penalty_paid: self.penalty_paid,
+            fee_paid: self.fee_paid,
+            penalty_accrued: self.penalty_accrued,
+            penalty_accrued_at: self.penalty_accrued_at,
+            endorsements: Vec::new(e),
+        }
+    }
+}
+
+// `LoanDetails` in version 2
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoanDetailsV2 {
+    pub who: Address,
+    pub inv_no: u32,
+    pub apr_bps: i128,
+    pub invoice_amount: i128,
+    pub loan_amount: i128,
+    pub repayment_date: u64,
+    pub released_at: u64,
+    pub status: LoanStatus,
+    pub grace_period: u64,
+    pub penalty_apr_bps: i128,
+    pub installments: Vec<Installment>,
+    pub amount_repaid: i128,
+    pub penalty_paid: i128,
+    pub fee_paid: i128,
+    pub penalty_accrued: i128,
+    pub penalty_accrued_at: u64,
+    pub endorsements: Vec<Address>,
+}
+
+impl LoanDetailsV2 {
+    // Earlier loans were split at the fixed insurance cut with no protocol fee
+    pub fn migrate(self) -> LoanDetails {
+        LoanDetails {
+            who: self.who,
+            inv_no: self.inv_no,
+            apr_bps: self.apr_bps,
+            invoice_amount: self.invoice_amount,
+            loan_amount: self.loan_amount,
+            repayment_date: self.repayment_date,
+            released_at: self.released_at,
+            status: self.status,
+            grace_period: self.grace_period,
+            penalty_apr_bps: self.penalty_apr_bps,
+            installments: self.installments,
+            amount_repaid: self.amount_repaid,
+            penalty_paid: self.penalty_paid,
+            fee_paid: self.fee_paid,
+            penalty_accrued: self.penalty_accrued,
+            penalty_accrued_at: self.penalty_accrued_at,
+            endorsements: self.endorsements,
+            insurance_fee_bps: INSURANCE_FEE_BPS,
+            protocol_fee_bps: 0,
+        }
+    }
+}
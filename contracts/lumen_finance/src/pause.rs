@@ -0,0 +1,47 @@
+use soroban_sdk::{contracttype, Env};
+
+use crate::{AdminDataKey, DataKey, Error};
+
+// Pool operations that can be switched off on their own during an incident.
+// Repayments are never paused so borrowers can always settle.
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Operation {
+    Deposit = 0,
+    // Withdrawals, redemptions and the withdrawal queue
+    Withdraw = 1,
+    RequestLoan = 2,
+    ApproveLoan = 3,
+    ClaimLoan = 4,
+}
+
+pub fn is_globally_paused(e: &Env) -> bool {
+    e.storage().instance().get(&DataKey::Paused).unwrap_or(false)
+}
+
+pub fn put_globally_paused(e: &Env, paused: bool) {
+    e.storage().instance().set(&DataKey::Paused, &paused);
+}
+
+pub fn is_operation_paused(e: &Env, operation: Operation) -> bool {
+    let key = AdminDataKey::PAUSED(operation);
+    e.storage().instance().get(&key).unwrap_or(false)
+}
+
+pub fn put_operation_paused(e: &Env, operation: Operation, paused: bool) {
+    let key = AdminDataKey::PAUSED(operation);
+    if paused {
+        e.storage().instance().set(&key, &true);
+    } else {
+        e.storage().instance().remove(&key);
+    }
+}
+
+// An operation is off while the whole pool is paused or its own switch is set
+pub fn check_not_paused(e: &Env, operation: Operation) -> Result<(), Error> {
+    if is_globally_paused(e) || is_operation_paused(e, operation) {
+        return Err(Error::Paused);
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use soroban_sdk::{contracttype, Address, Env};
+
+use crate::{admin::read_administrator, AdminDataKey, Error, LOAN_BUMP_AMOUNT, LOAN_LIFETIME_THRESHOLD};
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Role {
+    // Prices, approves and rejects loans
+    Underwriter = 0,
+    // Whitelists borrowers
+    KycOperator = 1,
+    // Handles defaults
+    RiskManager = 2,
+    Pauser = 3,
+    // Moves untracked funds out of the pool
+    Treasurer = 4,
+}
+
+pub fn has_role(e: &Env, role: Role, who: &Address) -> bool {
+    let key = AdminDataKey::ROLE(role, who.clone());
+    let granted = e.storage().persistent().get(&key).unwrap_or(false);
+    if granted {
+        e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    }
+    granted
+}
+
+pub fn write_role(e: &Env, role: Role, who: &Address) {
+    let key = AdminDataKey::ROLE(role, who.clone());
+    e.storage().persistent().set(&key, &true);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+pub fn remove_role(e: &Env, role: Role, who: &Address) {
+    let key = AdminDataKey::ROLE(role, who.clone());
+    e.storage().persistent().remove(&key);
+}
+
+// `operator` must authorize and hold `role`. The admin holds every role so a
+// pool works before any role is granted.
+pub fn check_role(e: &Env, role: Role, operator: &Address) -> Result<(), Error> {
+    operator.require_auth();
+    if has_role(e, role, operator) || read_administrator(e).ok().as_ref() == Some(operator) {
+        return Ok(());
+    }
+    Err(Error::MissingRole)
+}
@@ -1,14 +1,23 @@
 #![cfg(test)]
 extern crate std;
 
-use crate::{token, LumenFinance, LumenFinanceClient};
+use crate::{
+    migration::LoanDetailsV1, token, AdminDataKey, ChangeStatus, DataKey, Error, Installment, LoanDetails, LoanStatus,
+    Lockup, LumenFinance, LumenFinanceClient, Operation, ParamChange, PoolConfig, Role, Tranche, WithdrawStatus,
+    LOAN_BUMP_AMOUNT, LOAN_LIFETIME_THRESHOLD, MAX_APR_BPS, SCHEMA_VERSION, VIRTUAL_SHARES,
+};
 
+use lumen_insurance_contract::{LumenInsurance, LumenInsuranceClient};
 use soroban_sdk::{
     symbol_short,
-    testutils::{Address as _, AuthorizedFunction, AuthorizedInvocation, Ledger},
-    Address, BytesN, Env, IntoVal
+    testutils::{storage::Persistent as _, Address as _, AuthorizedFunction, AuthorizedInvocation, Events, Ledger},
+    vec, Address, BytesN, Env, IntoVal, Symbol
 };
 
+// 73 days at 50% APR discounts an invoice by exactly 10%
+const REPAYMENT_DATE: u64 = 73 * 86_400;
+const APR_BPS: i128 = 5_000;
+
 fn create_token_contract<'a>(e: &Env, admin: &Address) -> token::Client<'a> {
     token::Client::new(e, &e.register_stellar_asset_contract(admin.clone()))
 }
@@ -21,10 +30,43 @@ fn create_lumenfinance_contract<'a>(
     insurance: &Address,
 ) -> LumenFinanceClient<'a> {
     let lumen = LumenFinanceClient::new(e, &e.register_contract(None, LumenFinance));
+    create_insurance_contract(e, insurance, admin, usdc, &lumen.address);
     lumen.initialize(token_wasm_hash, usdc, admin, insurance);
     lumen
 }
 
+// Insurance reserve for `pool` covering all of a loss by default
+fn create_insurance_contract<'a>(
+    e: &Env,
+    insurance: &Address,
+    admin: &Address,
+    usdc: &Address,
+    pool: &Address,
+) -> LumenInsuranceClient<'a> {
+    let insurance = LumenInsuranceClient::new(e, &e.register_contract(insurance, LumenInsurance));
+    insurance.initialize(admin, usdc, pool, &10_000);
+    insurance
+}
+
+// Queue `change` and execute it straight away. The first call drops the
+// timelock delay to zero, then winds the clock back to where the test was.
+fn apply_change(e: &Env, lumen: &LumenFinanceClient, change: ParamChange) {
+    let delay = lumen.get_timelock_delay();
+    if delay > 0 {
+        let now = e.ledger().timestamp();
+        let change_id = lumen.queue_change(&ParamChange::TimelockDelay(0));
+        e.ledger().with_mut(|li| {
+            li.timestamp = now + delay;
+        });
+        lumen.execute_change(&change_id);
+        e.ledger().with_mut(|li| {
+            li.timestamp = now;
+        });
+    }
+    let change_id = lumen.queue_change(&change);
+    lumen.execute_change(&change_id);
+}
+
 fn install_token_wasm(e: &Env) -> BytesN<32> {
     soroban_sdk::contractimport!(
         file = "token/soroban_token_contract.wasm"
@@ -52,14 +94,14 @@ fn test_deposit() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
     // Balance before
     assert_eq!(usdc.balance(&depositor), 1000);
     assert_eq!(usdc.balance(&lumen.address), 0);
 
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
     // Balance after
     assert_eq!(token_share.balance(&depositor), 1000);
@@ -87,15 +129,15 @@ fn test_withdraw() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
     usdc.mint(&depositor2, &200);
-    lumen.deposit(&depositor2, &200);
+    lumen.deposit(&depositor2, &Tranche::Junior, &200, &0, &None);
 
     // Withdraw
-    lumen.withdraw(&depositor, &500);
+    lumen.withdraw(&depositor, &Tranche::Junior, &500, &0, &None);
 
     // Check balance
     assert_eq!(token_share.balance(&depositor), 500);
@@ -103,7 +145,6 @@ fn test_withdraw() {
 }
 
 #[test]
-#[should_panic]
 fn test_request_loan_not_whitelisted() {
     let e = Env::default();
     e.mock_all_auths();
@@ -123,12 +164,15 @@ fn test_request_loan_not_whitelisted() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
+    assert_eq!(
+        lumen.try_request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE),
+        Err(Ok(Error::NotWhitelisted))
+    );
 }
 
 #[test]
@@ -151,13 +195,14 @@ fn test_request_loan() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    assert_eq!(lumen.get_loan_id(&borrower, &231u32), loan_id);
 }
 
 #[test]
@@ -180,18 +225,17 @@ fn test_approve_loan() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
-    lumen.approve_loan(&231u32, &10i128);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
 }
 
 #[test]
-#[should_panic]
 fn test_approve_loan_bad_fee_rate() {
     let e = Env::default();
     e.mock_all_auths();
@@ -211,14 +255,47 @@ fn test_approve_loan_bad_fee_rate() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &(MAX_APR_BPS + 1)), Err(Ok(Error::FeeRateTooHigh)));
+}
+
+#[test]
+fn test_approve_loan_discount_exceeds_invoice() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
-    lumen.approve_loan(&231u32, &101i128);
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+
+    // 400 days at 100% APR discounts more than the whole invoice
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &(400 * 86_400));
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &MAX_APR_BPS), Err(Ok(Error::InvalidAdvance)));
+    // Priced at an APR that leaves an advance it goes through
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    assert!(usdc.balance(&borrower) > 0);
 }
 
 #[test]
@@ -241,22 +318,21 @@ fn test_claim_loan() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
-    lumen.approve_loan(&231u32, &10i128);
-    lumen.claim_loan(&231u32);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
 
     let amount_after_fee: i128 = 800 * 90/ 100;
     assert_eq!(usdc.balance(&borrower), amount_after_fee);
 }
 
 #[test]
-#[should_panic]
 fn test_repay_loan_not_time_yet() {
     let e = Env::default();
     e.mock_all_auths();
@@ -276,16 +352,16 @@ fn test_repay_loan_not_time_yet() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
-    lumen.approve_loan(&231u32, &10i128);
-    lumen.claim_loan(&231u32);
-    lumen.repay_loan(&231u32);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    assert_eq!(lumen.try_repay_loan(&loan_id), Err(Ok(Error::RepaymentDateNotReached)));
 }
 
 #[test]
@@ -308,33 +384,104 @@ fn test_repay_loan() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
-    lumen.approve_loan(&231u32, &10i128);
-    lumen.claim_loan(&231u32);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
     let amount_after_fee: i128 = 800 * 90/ 100; // same to loan.loan_amount
     assert_eq!(usdc.balance(&borrower), amount_after_fee);
 
     // Advance the time
     e.ledger().with_mut(|li| {
-        li.timestamp = 1745156 + 1;
+        li.timestamp = REPAYMENT_DATE + 1;
     });
 
     // Check repayment and portion that goes to insurance
-    let loan = lumen.get_loan_details(&231u32);
+    let loan = lumen.get_loan_details(&loan_id);
     let fees = loan.invoice_amount - loan.loan_amount;
     usdc.mint(&borrower, &fees); // mint back the fees
-    let (_, insurance_fee) = lumen.repay_loan(&231u32);
+    let (_, insurance_fee) = lumen.repay_loan(&loan_id);
     assert_eq!(usdc.balance(&borrower), 0);
     assert_eq!(usdc.balance(&lumen.address), 1000 + fees - insurance_fee);
     assert_eq!(lumen.get_fees_earned(), fees - insurance_fee);
 }
 
+#[test]
+fn test_repay_loan_without_insurance_contract() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    // A plain account rather than a reserve contract
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = LumenFinanceClient::new(&e, &e.register_contract(None, LumenFinance));
+    lumen.initialize(&install_token_wasm(&e), &usdc.address, &admin, &insurance);
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + 1;
+    });
+
+    // The premium is still paid out, only its notification fails
+    usdc.mint(&borrower, &80);
+    let (_, insurance_fee) = lumen.repay_loan(&loan_id);
+    assert_eq!(insurance_fee, 8);
+    assert_eq!(usdc.balance(&insurance), 8);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Repaid);
+}
+
+#[test]
+fn test_write_off_without_insurance_contract() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    // A plain account rather than a reserve contract
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = LumenFinanceClient::new(&e, &e.register_contract(None, LumenFinance));
+    lumen.initialize(&install_token_wasm(&e), &usdc.address, &admin, &insurance);
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + 30 * 86_400 + 1;
+    });
+    lumen.mark_default(&admin, &loan_id);
+
+    // No cover can be drawn, the whole loss falls to LPs
+    assert_eq!(lumen.write_off(&admin, &loan_id, &0), (0, 720));
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::WrittenOff);
+    assert_eq!(lumen.get_nav(), 1000 - 720);
+}
+
 #[test]
 fn test_withdraw_with_fees_earned() {
     let e = Env::default();
@@ -355,28 +502,1950 @@ fn test_withdraw_with_fees_earned() {
         &insurance,
     );
 
-    let token_share = token::Client::new(&e, &lumen.share_id());
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
 
     usdc.mint(&depositor, &1000);
-    lumen.deposit(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
 
-    lumen.whitelist(&borrower);
-    lumen.request_loan(&borrower, &800, &231u32, &1745156);
-    lumen.approve_loan(&231u32, &10i128);
-    lumen.claim_loan(&231u32);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
 
     // Advance the time
     e.ledger().with_mut(|li| {
-        li.timestamp = 1745156 + 1;
+        li.timestamp = REPAYMENT_DATE + 1;
     });
 
     // Check repayment and portion that goes to insurance
-    let loan = lumen.get_loan_details(&231u32);
+    let loan = lumen.get_loan_details(&loan_id);
     let fees = loan.invoice_amount - loan.loan_amount;
     usdc.mint(&borrower, &fees); // mint back the fees
-    let (_, insurance_fee) = lumen.repay_loan(&231u32);
+    let (_, insurance_fee) = lumen.repay_loan(&loan_id);
+
+    // Check withdrawal with earnings, the virtual shares keep their part of
+    // the fee in the pool
+    lumen.withdraw(&depositor, &Tranche::Junior, &1000, &0, &None);
+    let lp_fee = fees - insurance_fee;
+    assert_eq!(usdc.balance(&depositor), 1000 + lp_fee * 1000 / (1000 + VIRTUAL_SHARES));
+}
+
+#[test]
+fn test_nav_includes_outstanding_loans() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let depositor2 = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    // Releasing the loan does not move the share price
+    assert_eq!(usdc.balance(&lumen.address), 280);
+    assert_eq!(lumen.get_nav(), 1000);
+    assert_eq!(lumen.get_share_price(&Tranche::Junior), 10_000_000);
+
+    // A new depositor gets shares at NAV, not at the idle balance
+    usdc.mint(&depositor2, &1000);
+    lumen.deposit(&depositor2, &Tranche::Junior, &1000, &0, &None);
+    assert_eq!(token_share.balance(&depositor2), 1000);
+
+    // Half way through the tenor half of the LP fee has accrued
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE / 2;
+    });
+    let lp_fee = 80 * 90 / 100;
+    assert_eq!(lumen.get_nav(), 2000 + lp_fee / 2);
+    // Shared with the other depositor and the virtual shares
+    assert_eq!(lumen.get_user_share_balance(&depositor, &Tranche::Junior), 1000 + lp_fee / 2 * 1000 / (2000 + VIRTUAL_SHARES));
+
+    // Repayment realises the fee without a jump in NAV
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    let nav_before = lumen.get_nav();
+    usdc.mint(&borrower, &80);
+    lumen.repay_loan(&loan_id);
+    assert_eq!(nav_before, 2000 + lp_fee);
+    assert_eq!(lumen.get_nav(), nav_before);
+}
+
+#[test]
+fn test_overdue_loan_stops_accruing() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &100_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &100_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+
+    // A 10 day loan left overdue next to a 100 day loan still current
+    let short_id = lumen.request_loan(&borrower, &20_000, &231u32, &(10 * 86_400));
+    let long_id = lumen.request_loan(&borrower, &50_000, &232u32, &(100 * 86_400));
+    for loan_id in [short_id, long_id] {
+        lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+        lumen.claim_loan(&loan_id);
+    }
+    let lp_fee = |loan_id: u64| {
+        let loan = lumen.get_loan_details(&loan_id);
+        let fee = loan.invoice_amount - loan.loan_amount;
+        fee - fee * 1_000 / 10_000
+    };
+    let (short_fee, long_fee) = (lp_fee(short_id), lp_fee(long_id));
+
+    // The overdue loan earned its fee by its repayment date and nothing since
+    e.ledger().with_mut(|li| {
+        li.timestamp = 50 * 86_400;
+    });
+    assert_eq!(lumen.get_nav(), 100_000 + short_fee + long_fee / 2);
+
+    // Repaying it late only adds the LP share of the penalty
+    let nav_before = lumen.get_nav();
+    let amount_due = lumen.get_amount_due(&short_id);
+    usdc.mint(&borrower, &amount_due);
+    lumen.repay_loan(&short_id);
+    let penalty = lumen.get_loan_details(&short_id).penalty_paid;
+    assert_eq!(lumen.get_nav(), nav_before + penalty - penalty * 1_000 / 10_000);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = 100 * 86_400;
+    });
+    usdc.mint(&borrower, &lumen.get_amount_due(&long_id));
+    lumen.repay_loan(&long_id);
+    assert_eq!(lumen.get_nav(), 100_000 + short_fee + long_fee + penalty - penalty * 1_000 / 10_000);
+}
+
+#[test]
+fn test_vault_mint_and_redeem() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let depositor2 = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
+
+    usdc.mint(&depositor, &1000);
+    assert_eq!(lumen.preview_deposit(&Tranche::Junior, &1000), 1000);
+    assert_eq!(lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None), 1000);
+
+    // Lend out and let the fee fully accrue so a share is worth more than 1 USDC
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    assert_eq!(lumen.total_assets(&Tranche::Junior), 1072);
+    // The virtual shares hold their part of the fee
+    assert_eq!(lumen.convert_to_assets(&Tranche::Junior, &1000), 1036);
+    assert_eq!(lumen.convert_to_shares(&Tranche::Junior, &1036), 1000);
+
+    // Minting rounds the price up, depositing rounds the shares down
+    let cost = lumen.preview_mint(&Tranche::Junior, &100);
+    assert_eq!(cost, 104);
+    assert_eq!(lumen.preview_deposit(&Tranche::Junior, &cost), 100);
+    usdc.mint(&depositor2, &cost);
+    assert_eq!(lumen.mint(&depositor2, &Tranche::Junior, &100, &0, &None), cost);
+    assert_eq!(token_share.balance(&depositor2), 100);
+    assert_eq!(usdc.balance(&depositor2), 0);
+
+    // Only the idle USDC can be withdrawn while the loan is out
+    assert_eq!(lumen.max_withdraw(&depositor, &Tranche::Junior), 280 + cost);
+    assert_eq!(lumen.preview_withdraw(&Tranche::Junior, &103), 100);
+    assert_eq!(lumen.preview_redeem(&Tranche::Junior, &100), 103);
+    assert_eq!(lumen.redeem(&depositor2, &Tranche::Junior, &100, &0, &None), 103);
+    assert_eq!(usdc.balance(&depositor2), 103);
+    assert_eq!(lumen.get_shares(&Tranche::Junior), 1000);
+}
+
+#[test]
+fn test_errors() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+    let token_wasm_hash = install_token_wasm(&e);
+
+    // Nothing works before initialization
+    let uninitialized = LumenFinanceClient::new(&e, &e.register_contract(None, LumenFinance));
+    assert_eq!(uninitialized.try_deposit(&depositor, &Tranche::Junior, &1000, &0, &None), Err(Ok(Error::NotInitialized)));
+    assert_eq!(uninitialized.try_get_nav(), Err(Ok(Error::NotInitialized)));
+    assert_eq!(uninitialized.try_quote_discount(&36_500, &7u32, &1_000i128), Err(Ok(Error::NotInitialized)));
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &token_wasm_hash,
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+    assert_eq!(
+        lumen.try_initialize(&token_wasm_hash, &usdc.address, &admin, &insurance),
+        Err(Ok(Error::AlreadyInitialized))
+    );
+
+    usdc.mint(&depositor, &1000);
+    assert_eq!(lumen.try_deposit(&depositor, &Tranche::Junior, &0, &0, &None), Err(Ok(Error::InvalidAmount)));
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    assert_eq!(lumen.try_withdraw(&depositor, &Tranche::Junior, &1001, &0, &None), Err(Ok(Error::InsufficientShares)));
+
+    assert_eq!(lumen.try_get_loan_details(&1u64), Err(Ok(Error::LoanNotFound)));
+    assert_eq!(lumen.try_approve_loan(&admin, &1u64, &APR_BPS), Err(Ok(Error::LoanNotFound)));
+
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &2000, &231u32, &REPAYMENT_DATE);
+    assert_eq!(lumen.try_claim_loan(&loan_id), Err(Ok(Error::NotApproved)));
+    assert_eq!(lumen.try_repay_loan(&loan_id), Err(Ok(Error::NotReleased)));
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &(APR_BPS / 2)), Err(Ok(Error::AlreadyApproved)));
+    assert_eq!(lumen.try_claim_loan(&loan_id), Err(Ok(Error::InsufficientLiquidity)));
+
+    let loan_id2 = lumen.request_loan(&borrower, &800, &232u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id2, &APR_BPS);
+    lumen.claim_loan(&loan_id2);
+    assert_eq!(lumen.try_claim_loan(&loan_id2), Err(Ok(Error::AlreadyReleased)));
+    assert_eq!(lumen.try_withdraw(&depositor, &Tranche::Junior, &1000, &0, &None), Err(Ok(Error::InsufficientLiquidity)));
+}
+
+#[test]
+fn test_loan_status_transitions() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+
+    // Rejected and cancelled loans can no longer be approved or claimed
+    let rejected_id = lumen.request_loan(&borrower, &100, &1u32, &REPAYMENT_DATE);
+    lumen.reject_loan(&admin, &rejected_id);
+    assert_eq!(lumen.get_loan_details(&rejected_id).status, LoanStatus::Rejected);
+    assert_eq!(lumen.try_approve_loan(&admin, &rejected_id, &APR_BPS), Err(Ok(Error::InvalidStatusTransition)));
+
+    let cancelled_id = lumen.request_loan(&borrower, &100, &2u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &cancelled_id, &APR_BPS);
+    lumen.cancel_loan(&cancelled_id);
+    assert_eq!(lumen.get_loan_details(&cancelled_id).status, LoanStatus::Cancelled);
+    assert_eq!(lumen.try_claim_loan(&cancelled_id), Err(Ok(Error::InvalidStatusTransition)));
+
+    // A released loan cannot be re-priced, cancelled or paid out twice
+    let loan_id = lumen.request_loan(&borrower, &800, &3u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Released);
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &(APR_BPS / 2)), Err(Ok(Error::InvalidStatusTransition)));
+    assert_eq!(lumen.try_cancel_loan(&loan_id), Err(Ok(Error::InvalidStatusTransition)));
+    assert_eq!(lumen.try_claim_loan(&loan_id), Err(Ok(Error::AlreadyReleased)));
+    assert_eq!(usdc.balance(&borrower), 720);
+
+    // Overdue only once the repayment date has passed, and still repayable
+    assert_eq!(lumen.try_mark_overdue(&loan_id), Err(Ok(Error::NotOverdue)));
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + 1;
+    });
+    lumen.mark_overdue(&loan_id);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Overdue);
+
+    usdc.mint(&borrower, &80);
+    lumen.repay_loan(&loan_id);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Repaid);
+    assert_eq!(lumen.try_repay_loan(&loan_id), Err(Ok(Error::AlreadyRepaid)));
+}
+
+#[test]
+fn test_invoice_number_collision() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let borrower = Address::generate(&e);
+    let borrower2 = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    lumen.whitelist(&admin, &borrower);
+    lumen.whitelist(&admin, &borrower2);
+
+    // Reusing another borrower's invoice number gets a fresh loan id
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+    let loan_id2 = lumen.request_loan(&borrower2, &5000, &231u32, &REPAYMENT_DATE);
+    assert_ne!(loan_id, loan_id2);
+    assert_eq!(lumen.get_loan_details(&loan_id).who, borrower);
+    assert_eq!(lumen.get_loan_details(&loan_id).invoice_amount, 800);
+    assert_eq!(lumen.get_loan_id(&borrower2, &231u32), loan_id2);
+
+    // The same borrower cannot submit an invoice twice
+    assert_eq!(
+        lumen.try_request_loan(&borrower, &900, &231u32, &REPAYMENT_DATE),
+        Err(Ok(Error::DuplicateInvoice))
+    );
+    assert_eq!(lumen.try_get_loan_id(&borrower, &232u32), Err(Ok(Error::LoanNotFound)));
+}
+
+#[test]
+fn test_loans_in_persistent_storage() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &231u32, &REPAYMENT_DATE);
+
+    let loan_key = AdminDataKey::LOAN(loan_id);
+    let whitelist_key = AdminDataKey::WHITELISTED(borrower.clone());
+    e.as_contract(&lumen.address, || {
+        assert!(!e.storage().instance().has(&loan_key));
+        assert!(e.storage().persistent().has(&loan_key));
+        assert!(e.storage().persistent().has(&whitelist_key));
+        assert_eq!(e.storage().persistent().get_ttl(&loan_key), LOAN_BUMP_AMOUNT);
+    });
+
+    // Let the entries age, then the maintenance bump restores their TTL
+    e.ledger().with_mut(|li| {
+        li.sequence_number += LOAN_BUMP_AMOUNT - LOAN_LIFETIME_THRESHOLD + 1;
+    });
+    e.as_contract(&lumen.address, || {
+        assert!(e.storage().persistent().get_ttl(&loan_key) < LOAN_LIFETIME_THRESHOLD);
+    });
+    lumen.bump_loans(&vec![&e, loan_id]);
+    e.as_contract(&lumen.address, || {
+        assert_eq!(e.storage().persistent().get_ttl(&loan_key), LOAN_BUMP_AMOUNT);
+        assert_eq!(e.storage().persistent().get_ttl(&whitelist_key), LOAN_BUMP_AMOUNT);
+    });
+
+    assert_eq!(lumen.try_bump_loans(&vec![&e, loan_id + 1]), Err(Ok(Error::LoanNotFound)));
+}
+
+#[test]
+fn test_apr_pricing_by_tenor() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
 
-    // Check withdrawal with earnings
-    lumen.withdraw(&depositor, &1000);
-    assert_eq!(usdc.balance(&depositor), 1000 + fees - insurance_fee);
+    usdc.mint(&depositor, &100_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &100_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+
+    // Quotes scale with the tenor and round up in favour of the pool
+    assert_eq!(lumen.quote_discount(&36_500, &7u32, &1_000i128), 70);
+    assert_eq!(lumen.quote_discount(&36_500, &120u32, &1_000i128), 1_200);
+    assert_eq!(lumen.quote_discount(&100, &1u32, &1_000i128), 1);
+    assert_eq!(lumen.try_quote_discount(&100, &1u32, &(MAX_APR_BPS + 1)), Err(Ok(Error::FeeRateTooHigh)));
+
+    // The advance is priced from the day the loan is claimed
+    let short_id = lumen.request_loan(&borrower, &36_500, &1u32, &(7 * 86_400));
+    let long_id = lumen.request_loan(&borrower, &36_500, &2u32, &(130 * 86_400));
+    lumen.approve_loan(&admin, &short_id, &1_000i128);
+    lumen.approve_loan(&admin, &long_id, &1_000i128);
+    e.ledger().with_mut(|li| {
+        li.timestamp = 10 * 86_400;
+    });
+    assert_eq!(lumen.try_claim_loan(&short_id), Err(Ok(Error::InvalidRepaymentDate)));
+    lumen.claim_loan(&long_id);
+    assert_eq!(lumen.get_loan_details(&long_id).loan_amount, 36_500 - 1_200);
+    assert_eq!(usdc.balance(&borrower), 36_500 - 1_200);
+
+    assert_eq!(
+        lumen.try_request_loan(&borrower, &800, &3u32, &(10 * 86_400)),
+        Err(Ok(Error::InvalidRepaymentDate))
+    );
+}
+
+#[test]
+fn test_fee_split_in_basis_points() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    assert_eq!(lumen.get_insurance_fee_rate(), 1_000);
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+
+    // A 1.25% APR is expressible and reported back in basis points
+    let loan_id = lumen.request_loan(&borrower, &850, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &125i128);
+    assert_eq!(lumen.get_loan_details(&loan_id).apr_bps, 125);
+    lumen.cancel_loan(&loan_id);
+
+    // A fee of 85 leaves 8.5 for insurance, rounded down in favour of LPs
+    let loan_id = lumen.request_loan(&borrower, &850, &232u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    assert_eq!(lumen.get_loan_details(&loan_id).loan_amount, 765);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    usdc.mint(&borrower, &85);
+    let (_, insurance_fee) = lumen.repay_loan(&loan_id);
+    assert_eq!(insurance_fee, 8);
+    assert_eq!(usdc.balance(&insurance), 8);
+    assert_eq!(lumen.get_fees_earned(), 77);
+}
+
+#[test]
+fn test_late_payment_penalty() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &100_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &100_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+
+    // 10 days of grace, then 36.5% APR on the invoice, i.e. 0.1% a day
+    let grace_period = 10 * 86_400;
+    apply_change(
+        &e,
+        &lumen,
+        ParamChange::PoolConfig(PoolConfig { grace_period, penalty_apr_bps: 3_650, ..lumen.get_config() }),
+    );
+    assert_eq!(lumen.get_late_payment_terms(), (grace_period, 3_650));
+
+    let loan_id = lumen.request_loan(&borrower, &80_000, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    assert_eq!(lumen.get_amount_due(&loan_id), 80_000);
+
+    // Nothing extra within the grace period
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + grace_period;
+    });
+    assert_eq!(lumen.get_amount_due(&loan_id), 80_000);
+
+    // Penalty accrues per second once the grace period is over
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + grace_period + 86_400 / 2;
+    });
+    assert_eq!(lumen.get_amount_due(&loan_id), 80_040);
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + grace_period + 5 * 86_400;
+    });
+    assert_eq!(lumen.get_amount_due(&loan_id), 80_400);
+
+    // Penalty is split between LPs and insurance like the fee
+    usdc.mint(&borrower, &8_400);
+    let (repaid, insurance_fee) = lumen.repay_loan(&loan_id);
+    assert_eq!(repaid, 80_400);
+    assert_eq!(insurance_fee, 800 + 40);
+    assert_eq!(lumen.get_fees_earned(), 7_200 + 360);
+    assert_eq!(usdc.balance(&borrower), 0);
+    assert_eq!(lumen.get_amount_due(&loan_id), 0);
+}
+
+#[test]
+fn test_partial_repayments() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &100_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &100_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+    apply_change(
+        &e,
+        &lumen,
+        ParamChange::PoolConfig(PoolConfig { grace_period: 0, penalty_apr_bps: 3_650, ..lumen.get_config() }),
+    );
+
+    let loan_id = lumen.request_loan(&borrower, &80_000, &231u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    // A first tranche only pays down the fee and leaves NAV untouched
+    e.ledger().with_mut(|li| {
+        li.timestamp = 10 * 86_400;
+    });
+    let nav = lumen.get_nav();
+    assert_eq!(lumen.repay_partial(&loan_id, &5_000), (5_000, 500));
+    assert_eq!(lumen.get_nav(), nav);
+    let loan = lumen.get_loan_details(&loan_id);
+    assert_eq!((loan.amount_repaid, loan.fee_paid, loan.status), (5_000, 5_000, LoanStatus::Released));
+    assert_eq!(lumen.get_amount_due(&loan_id), 75_000);
+    assert_eq!(lumen.get_fees_earned(), 4_500);
+
+    // A day late the next tranche covers the penalty, the rest of the fee and
+    // then principal
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + 86_400;
+    });
+    assert_eq!(lumen.get_amount_due(&loan_id), 75_075);
+    usdc.mint(&borrower, &8_075);
+    assert_eq!(lumen.repay_partial(&loan_id, &4_000), (4_000, 7 + 300));
+    let loan = lumen.get_loan_details(&loan_id);
+    assert_eq!((loan.penalty_paid, loan.fee_paid, loan.amount_repaid), (75, 8_000, 9_000));
+    assert_eq!(lumen.get_amount_due(&loan_id), 71_075);
+
+    // Overpaying only collects what is due and closes the loan
+    assert_eq!(lumen.repay_partial(&loan_id, &100_000), (71_075, 0));
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Repaid);
+    assert_eq!(usdc.balance(&borrower), 0);
+    assert_eq!(usdc.balance(&insurance), 807);
+    assert_eq!(lumen.get_nav(), 100_000 + 7_200 + 68);
+    assert_eq!(lumen.try_repay_partial(&loan_id, &1), Err(Ok(Error::AlreadyRepaid)));
+}
+
+#[test]
+fn test_installment_schedule() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &100_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &100_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+    apply_change(
+        &e,
+        &lumen,
+        ParamChange::PoolConfig(PoolConfig { grace_period: 0, penalty_apr_bps: 3_650, ..lumen.get_config() }),
+    );
+
+    let loan_id = lumen.request_loan(&borrower, &36_500, &231u32, &(60 * 86_400));
+    let installment = |day: u64, amount: i128| Installment { due_date: day * 86_400, amount };
+
+    // The schedule has to add up to the invoice and end on the repayment date
+    assert_eq!(
+        lumen.try_approve_loan_with_schedule(&admin, &loan_id, &1_000i128, &vec![&e, installment(30, 18_250), installment(60, 18_000)]),
+        Err(Ok(Error::InvalidSchedule))
+    );
+    assert_eq!(
+        lumen.try_approve_loan_with_schedule(&admin, &loan_id, &1_000i128, &vec![&e, installment(30, 18_250), installment(50, 18_250)]),
+        Err(Ok(Error::InvalidSchedule))
+    );
+
+    // Each installment is discounted for its own tenor
+    lumen.approve_loan_with_schedule(&admin, &loan_id, &1_000i128, &vec![&e, installment(30, 18_250), installment(60, 18_250)]);
+    lumen.claim_loan(&loan_id);
+    assert_eq!(usdc.balance(&borrower), 36_500 - 150 - 300);
+
+    // Paying the first installment on time keeps the loan current
+    e.ledger().with_mut(|li| {
+        li.timestamp = 29 * 86_400;
+    });
+    usdc.mint(&borrower, &450);
+    lumen.repay_partial(&loan_id, &18_250);
+    e.ledger().with_mut(|li| {
+        li.timestamp = 31 * 86_400;
+    });
+    assert_eq!(lumen.try_mark_overdue(&loan_id), Err(Ok(Error::NotOverdue)));
+    assert_eq!(lumen.get_amount_due(&loan_id), 18_250);
+
+    // Missing the second one makes the loan overdue and charges a penalty on it
+    e.ledger().with_mut(|li| {
+        li.timestamp = 61 * 86_400;
+    });
+    lumen.mark_overdue(&loan_id);
+    usdc.mint(&borrower, &19);
+    // The whole fee, and its insurance cut, went with the first installment
+    assert_eq!(lumen.repay_loan(&loan_id), (18_250 + 19, 1));
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Repaid);
+}
+
+#[test]
+fn test_default_and_write_off() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &100_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &100_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+    assert_eq!(lumen.get_default_window(), 30 * 86_400);
+
+    let loan_id = lumen.request_loan(&borrower, &80_000, &241u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    // Pays the 8_000 fee and 2_000 of principal, then stops paying
+    usdc.mint(&borrower, &2_000);
+    lumen.repay_partial(&loan_id, &10_000);
+
+    // Not defaultable until the window after the due date has passed
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + 30 * 86_400;
+    });
+    assert_eq!(lumen.try_mark_default(&admin, &loan_id), Err(Ok(Error::DefaultWindowNotElapsed)));
+    assert_eq!(lumen.try_write_off(&admin, &loan_id, &0), Err(Ok(Error::InvalidStatusTransition)));
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE + 30 * 86_400 + 1;
+    });
+    lumen.mark_default(&admin, &loan_id);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Defaulted);
+    assert_eq!(lumen.get_amount_due(&loan_id), 0);
+
+    // The principal left is provisioned, NAV is only the idle balance
+    assert_eq!(lumen.get_impairments(), 70_000);
+    assert_eq!(lumen.get_nav(), 100_000 - 72_000 + 10_000 - 800);
+    assert_eq!(lumen.try_mark_default(&admin, &loan_id), Err(Ok(Error::InvalidStatusTransition)));
+
+    // The reserve covers 60% of this loan's loss
+    let insurance_reserve = LumenInsuranceClient::new(&e, &insurance);
+    assert_eq!(insurance_reserve.get_reserve(), 800);
+    usdc.mint(&admin, &50_000);
+    insurance_reserve.fund(&admin, &50_000);
+    insurance_reserve.set_coverage(&loan_id, &6_000);
+    usdc.mint(&admin, &20_000);
+    assert_eq!(lumen.write_off(&admin, &loan_id, &20_000), (30_000, 20_000));
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::WrittenOff);
+    assert_eq!(usdc.balance(&insurance), 20_000 + 800);
+    assert_eq!(insurance_reserve.get_reserve(), 20_000 + 800);
+
+    // LPs absorb the rest of the 72_000 advance
+    assert_eq!(lumen.get_impairments(), 0);
+    assert_eq!(lumen.get_nav(), 100_000 + 7_200 - 20_000);
+    assert_eq!(lumen.get_nav(), usdc.balance(&lumen.address));
+}
+
+#[test]
+fn test_senior_and_junior_tranches() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let senior = Address::generate(&e);
+    let junior = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+    assert_ne!(lumen.share_id(&Tranche::Senior), lumen.share_id(&Tranche::Junior));
+
+    // Seniors capped at 10% APR, juniors must stay at least 20% of NAV
+    apply_change(&e, &lumen, ParamChange::TrancheTerms(1_000, 2_000));
+    assert_eq!(lumen.get_tranche_terms(), (1_000, 2_000));
+
+    usdc.mint(&senior, &90_000);
+    usdc.mint(&junior, &22_500);
+    lumen.deposit(&senior, &Tranche::Senior, &90_000, &0, &None);
+    lumen.deposit(&junior, &Tranche::Junior, &10_000, &0, &None);
+    assert_eq!(lumen.get_junior_coverage(), 1_000);
+
+    // Not enough junior cover to lend
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &80_000, &251u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    assert_eq!(lumen.try_claim_loan(&loan_id), Err(Ok(Error::InsufficientJuniorCoverage)));
+
+    lumen.deposit(&junior, &Tranche::Junior, &12_500, &0, &None);
+    assert_eq!(lumen.get_junior_coverage(), 2_000);
+    lumen.claim_loan(&loan_id);
+
+    // Seniors earn their capped 10% APR over 73 days, juniors the rest of the 7_200 fee
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    assert_eq!(lumen.get_share_price(&Tranche::Senior), 10_200_000);
+    assert_eq!(lumen.total_assets(&Tranche::Senior), 91_800);
+    assert_eq!(lumen.total_assets(&Tranche::Junior), 22_500 + 5_400);
+    usdc.mint(&borrower, &8_000);
+    lumen.repay_loan(&loan_id);
+
+    // Juniors can only withdraw down to the minimum coverage
+    assert_eq!(lumen.max_withdraw(&junior, &Tranche::Junior), 4_950);
+    assert_eq!(lumen.max_withdraw(&senior, &Tranche::Senior), 91_800);
+    assert_eq!(
+        lumen.try_withdraw(&junior, &Tranche::Junior, &22_500, &0, &None),
+        Err(Ok(Error::InsufficientJuniorCoverage))
+    );
+
+    // A default hits juniors first, seniors keep their claim
+    let loan_id = lumen.request_loan(&borrower, &8_000, &252u32, &(2 * REPAYMENT_DATE));
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    LumenInsuranceClient::new(&e, &insurance).set_coverage(&loan_id, &0);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = 2 * REPAYMENT_DATE + 30 * 86_400 + 1;
+    });
+    let senior_assets = lumen.total_assets(&Tranche::Senior);
+    let junior_assets = lumen.total_assets(&Tranche::Junior);
+    lumen.mark_default(&admin, &loan_id);
+    assert_eq!(lumen.total_assets(&Tranche::Senior), senior_assets);
+    assert_eq!(lumen.total_assets(&Tranche::Junior), junior_assets - 7_200 - 720);
+
+    assert_eq!(lumen.write_off(&admin, &loan_id, &0), (0, 7_200));
+    assert_eq!(lumen.total_assets(&Tranche::Senior), senior_assets);
+    assert_eq!(lumen.total_assets(&Tranche::Junior), junior_assets - 7_200 - 720);
+}
+
+#[test]
+fn test_junior_request_held_by_coverage_does_not_block_seniors() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let junior = Address::generate(&e);
+    let senior = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    // Juniors exactly at the 20% minimum coverage, nothing lent out
+    usdc.mint(&junior, &2_000_000);
+    usdc.mint(&senior, &8_000_000);
+    lumen.deposit(&junior, &Tranche::Junior, &2_000_000, &0, &None);
+    lumen.deposit(&senior, &Tranche::Senior, &8_000_000, &0, &None);
+
+    // The junior request is parked behind the coverage
+    let junior_id = lumen.request_withdraw(&junior, &Tranche::Junior, &100_000);
+    assert_eq!(lumen.get_withdraw_request(&junior_id).status, WithdrawStatus::Pending);
+    assert_eq!(lumen.get_queue_position(&junior_id), 0);
+    assert_eq!(
+        lumen.try_withdraw(&junior, &Tranche::Junior, &1, &0, &None),
+        Err(Ok(Error::InsufficientJuniorCoverage))
+    );
+
+    // Seniors still exit, queued or straight away
+    let senior_id = lumen.request_withdraw(&senior, &Tranche::Senior, &1_000);
+    assert_eq!(lumen.get_withdraw_request(&senior_id).status, WithdrawStatus::Funded);
+    assert!(lumen.max_withdraw(&senior, &Tranche::Senior) > 0);
+    assert_eq!(lumen.withdraw(&senior, &Tranche::Senior, &1_000_000, &0, &None), 1_000_000);
+    assert_eq!(lumen.get_withdraw_request(&junior_id).status, WithdrawStatus::Pending);
+
+    // With fewer seniors to cover the parked request is funded
+    lumen.process_withdrawals();
+    assert_eq!(lumen.get_withdraw_request(&junior_id).status, WithdrawStatus::Funded);
+    assert_eq!(lumen.claim_withdraw(&junior_id), 100_000);
+    assert_eq!(lumen.get_queued_shares(&Tranche::Junior), 0);
+}
+
+#[test]
+fn test_withdrawal_queue() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let depositor2 = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
+
+    usdc.mint(&depositor, &1000);
+    usdc.mint(&depositor2, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.deposit(&depositor2, &Tranche::Junior, &1000, &0, &None);
+
+    // Most of the pool is lent out
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &1600, &261u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    assert_eq!(lumen.try_withdraw(&depositor, &Tranche::Junior, &1000, &0, &None), Err(Ok(Error::InsufficientLiquidity)));
+
+    // Queue up instead, the shares are escrowed
+    let first = lumen.request_withdraw(&depositor, &Tranche::Junior, &1000);
+    assert_eq!(token_share.balance(&depositor), 0);
+    assert_eq!(lumen.get_withdraw_request(&first).status, WithdrawStatus::Pending);
+    assert_eq!(lumen.try_claim_withdraw(&first), Err(Ok(Error::WithdrawalNotFunded)));
+
+    let cancelled = lumen.request_withdraw(&depositor2, &Tranche::Junior, &500);
+    lumen.cancel_withdraw(&cancelled);
+    assert_eq!(token_share.balance(&depositor2), 1000);
+    assert_eq!(lumen.try_cancel_withdraw(&cancelled), Err(Ok(Error::WithdrawalNotPending)));
+
+    let second = lumen.request_withdraw(&depositor2, &Tranche::Junior, &500);
+    assert_eq!(lumen.get_queue_position(&first), 0);
+    assert_eq!(lumen.get_queue_position(&second), 1);
+    assert_eq!(lumen.get_queued_shares(&Tranche::Junior), 1500);
+
+    // No jumping the queue with an instant withdrawal
+    assert_eq!(lumen.max_withdraw(&depositor2, &Tranche::Junior), 0);
+    assert_eq!(lumen.try_withdraw(&depositor2, &Tranche::Junior, &100, &0, &None), Err(Ok(Error::WithdrawalQueueNotEmpty)));
+
+    // The repayment funds both requests in order at the share price then
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    usdc.mint(&borrower, &160);
+    lumen.repay_loan(&loan_id);
+    // The virtual shares keep their part of the fee in the pool
+    assert_eq!(lumen.get_withdraw_request(&first).amount, 1048);
+    assert_eq!(lumen.get_withdraw_request(&second).amount, 524);
+    assert_eq!(lumen.get_queued_shares(&Tranche::Junior), 0);
+    assert_eq!(lumen.get_withdraw_reserve(), 1048 + 524);
+
+    // Funded requests are out of NAV, only the remaining 500 shares are left
+    assert_eq!(lumen.get_nav(), 572);
+    assert_eq!(lumen.get_shares(&Tranche::Junior), 500);
+
+    assert_eq!(lumen.claim_withdraw(&first), 1048);
+    assert_eq!(usdc.balance(&depositor), 1048);
+    assert_eq!(lumen.try_claim_withdraw(&first), Err(Ok(Error::WithdrawalNotFunded)));
+    assert_eq!(lumen.claim_withdraw(&second), 524);
+    assert_eq!(lumen.get_withdraw_reserve(), 0);
+    assert_eq!(usdc.balance(&lumen.address), 572);
+}
+
+#[test]
+fn test_cancelled_withdrawals_do_not_block_repayments() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let attacker = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &1000);
+    usdc.mint(&attacker, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.deposit(&attacker, &Tranche::Junior, &1000, &0, &None);
+
+    lumen.whitelist(&admin, &borrower);
+    // The whole pool is lent out
+    let loan_id = lumen.request_loan(&borrower, &2223, &261u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    // Fill the queue with dead entries ahead of a real request
+    e.budget().reset_unlimited();
+    let dead: std::vec::Vec<u64> = (0..30).map(|_| lumen.request_withdraw(&attacker, &Tranche::Junior, &1)).collect();
+    for request_id in dead {
+        lumen.cancel_withdraw(&request_id);
+    }
+    let request_id = lumen.request_withdraw(&depositor, &Tranche::Junior, &1000);
+    // Queuing it walked one batch of the dead entries, the ones beyond the
+    // next batch are counted without being read
+    assert_eq!(lumen.get_queue_position(&request_id), 10);
+
+    // Each call only walks one batch of the queue, so the repayment goes
+    // through and `process_withdrawals` carries on from there
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    usdc.mint(&borrower, &223);
+    e.budget().reset_default();
+    lumen.repay_loan(&loan_id);
+    assert_eq!(lumen.get_withdraw_request(&request_id).status, WithdrawStatus::Pending);
+    assert_eq!(lumen.get_queue_position(&request_id), 0);
+
+    lumen.process_withdrawals();
+    assert_eq!(lumen.get_withdraw_request(&request_id).status, WithdrawStatus::Pending);
+    lumen.process_withdrawals();
+    assert_eq!(lumen.get_withdraw_request(&request_id).status, WithdrawStatus::Funded);
+}
+
+#[test]
+fn test_deposit_lockup() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let depositor2 = Address::generate(&e);
+    let senior = Address::generate(&e);
+    let senior2 = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    // 30 day lockup, no early exits
+    let lockup_period = 30 * 86_400;
+    apply_change(&e, &lumen, ParamChange::LockupTerms(lockup_period, None));
+    apply_change(&e, &lumen, ParamChange::TrancheTerms(0, 2_000));
+
+    usdc.mint(&depositor, &1000);
+    usdc.mint(&depositor2, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.deposit(&depositor2, &Tranche::Junior, &1000, &0, &None);
+    assert_eq!(lumen.get_lockup_end(&depositor, &Tranche::Junior), lockup_period);
+    assert_eq!(lumen.max_withdraw(&depositor, &Tranche::Junior), 0);
+    assert_eq!(lumen.try_withdraw(&depositor, &Tranche::Junior, &500, &0, &None), Err(Ok(Error::LockupNotExpired)));
+    assert_eq!(lumen.try_request_withdraw(&depositor, &Tranche::Junior, &500), Err(Ok(Error::LockupNotExpired)));
+
+    // 2% to leave early, kept by the LPs who stay
+    apply_change(&e, &lumen, ParamChange::LockupTerms(lockup_period, Some(200)));
+    assert_eq!(lumen.get_lockup_terms(), (lockup_period, Some(200)));
+    assert_eq!(lumen.max_withdraw(&depositor, &Tranche::Junior), 980);
+    assert_eq!(lumen.withdraw(&depositor, &Tranche::Junior, &500, &0, &None), 490);
+    assert_eq!(lumen.total_assets(&Tranche::Junior), 1510);
+
+    // Seniors keep the fee within their tranche
+    usdc.mint(&senior, &1000);
+    usdc.mint(&senior2, &1000);
+    lumen.deposit(&senior, &Tranche::Senior, &1000, &0, &None);
+    lumen.deposit(&senior2, &Tranche::Senior, &1000, &0, &None);
+    assert_eq!(lumen.withdraw(&senior, &Tranche::Senior, &1000, &0, &None), 980);
+    assert_eq!(lumen.total_assets(&Tranche::Senior), 1020);
+    assert_eq!(lumen.total_assets(&Tranche::Junior), 1510);
+
+    // No fee once the lockup is over
+    e.ledger().with_mut(|li| {
+        li.timestamp = lockup_period;
+    });
+    assert_eq!(lumen.withdraw(&depositor, &Tranche::Junior, &500, &0, &None), 502);
+}
+
+#[test]
+fn test_locked_shares_stay_with_the_pool() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let fresh = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let token_share = token::Client::new(&e, &lumen.share_id(&Tranche::Junior));
+
+    let lockup_period = 30 * 86_400;
+    apply_change(&e, &lumen, ParamChange::LockupTerms(lockup_period, Some(200)));
+
+    // The pool holds the shares, there is nothing to send to another address
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    assert_eq!(token_share.balance(&depositor), 0);
+    assert_eq!(lumen.get_locked_shares(&depositor, &Tranche::Junior), 1000);
+    assert_eq!(lumen.get_user_share_balance(&depositor, &Tranche::Junior), 1000);
+    assert!(token_share.try_transfer(&depositor, &fresh, &1000).is_err());
+
+    // Lend the pool out so a queued request waits
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &1112, &261u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    // Cancelling a request puts the locked shares back into the lockup
+    let request_id = lumen.request_withdraw(&depositor, &Tranche::Junior, &400);
+    assert_eq!(lumen.get_withdraw_request(&request_id).locked_shares, 400);
+    assert_eq!(lumen.get_locked_shares(&depositor, &Tranche::Junior), 600);
+    lumen.cancel_withdraw(&request_id);
+    assert_eq!(lumen.get_locked_shares(&depositor, &Tranche::Junior), 1000);
+    assert_eq!(token_share.balance(&depositor), 0);
+
+    // New terms do not move the unlock time of shares already deposited
+    apply_change(&e, &lumen, ParamChange::LockupTerms(365 * 86_400, None));
+    assert_eq!(lumen.get_lockup_end(&depositor, &Tranche::Junior), lockup_period);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = lockup_period;
+    });
+    assert_eq!(lumen.unlock_shares(&depositor, &Tranche::Junior), 1000);
+    assert_eq!(token_share.balance(&depositor), 1000);
+    assert_eq!(lumen.get_locked_shares(&depositor, &Tranche::Junior), 0);
+
+    // Unlocked shares move freely and leave without a fee
+    token_share.transfer(&depositor, &fresh, &1000);
+    let request_id = lumen.request_withdraw(&fresh, &Tranche::Junior, &1000);
+    assert_eq!(lumen.get_withdraw_request(&request_id).locked_shares, 0);
+    assert_eq!(lumen.get_withdraw_request(&request_id).exit_fee_bps, 0);
+}
+
+#[test]
+fn test_each_deposit_keeps_its_unlock_time() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let lockup_period = 30 * 86_400;
+    apply_change(&e, &lumen, ParamChange::LockupTerms(lockup_period, None));
+
+    // Adding funds on day 29 does not re-lock the first deposit
+    usdc.mint(&depositor, &1500);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    e.ledger().with_mut(|li| {
+        li.timestamp = 29 * 86_400;
+    });
+    lumen.deposit(&depositor, &Tranche::Junior, &500, &0, &None);
+    assert_eq!(
+        lumen.get_lockups(&depositor, &Tranche::Junior),
+        vec![
+            &e,
+            Lockup { share_amount: 1000, unlocks_at: lockup_period },
+            Lockup { share_amount: 500, unlocks_at: 29 * 86_400 + lockup_period }
+        ]
+    );
+    assert_eq!(lumen.get_lockup_end(&depositor, &Tranche::Junior), 29 * 86_400 + lockup_period);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = lockup_period;
+    });
+    assert_eq!(lumen.max_withdraw(&depositor, &Tranche::Junior), 1000);
+    assert_eq!(lumen.withdraw(&depositor, &Tranche::Junior, &1000, &0, &None), 1000);
+    assert_eq!(lumen.try_withdraw(&depositor, &Tranche::Junior, &1, &0, &None), Err(Ok(Error::LockupNotExpired)));
+    assert_eq!(lumen.get_locked_shares(&depositor, &Tranche::Junior), 500);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = 29 * 86_400 + lockup_period;
+    });
+    assert_eq!(lumen.unlock_shares(&depositor, &Tranche::Junior), 500);
+    assert_eq!(lumen.get_lockup_end(&depositor, &Tranche::Junior), 0);
+}
+
+#[test]
+fn test_slippage_and_deadline_guards() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &2000);
+    e.ledger().with_mut(|li| {
+        li.timestamp = 100;
+    });
+
+    assert_eq!(
+        lumen.try_deposit(&depositor, &Tranche::Junior, &1000, &0, &Some(99)),
+        Err(Ok(Error::DeadlinePassed))
+    );
+    assert_eq!(
+        lumen.try_deposit(&depositor, &Tranche::Junior, &1000, &1001, &None),
+        Err(Ok(Error::SlippageExceeded))
+    );
+    assert_eq!(lumen.deposit(&depositor, &Tranche::Junior, &1000, &1000, &Some(100)), 1000);
+
+    assert_eq!(
+        lumen.try_mint(&depositor, &Tranche::Junior, &100, &99, &None),
+        Err(Ok(Error::SlippageExceeded))
+    );
+    assert_eq!(lumen.mint(&depositor, &Tranche::Junior, &100, &100, &None), 100);
+
+    assert_eq!(
+        lumen.try_withdraw(&depositor, &Tranche::Junior, &500, &501, &None),
+        Err(Ok(Error::SlippageExceeded))
+    );
+    assert_eq!(
+        lumen.try_redeem(&depositor, &Tranche::Junior, &500, &0, &Some(99)),
+        Err(Ok(Error::DeadlinePassed))
+    );
+    assert_eq!(lumen.withdraw(&depositor, &Tranche::Junior, &500, &500, &Some(100)), 500);
+}
+
+#[test]
+fn test_donation_does_not_inflate_share_price() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let attacker = Address::generate(&e);
+    let victim = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    // Attacker takes the first share, then donates USDC straight to the pool
+    // to push the price of that share above the victim's deposit
+    usdc.mint(&attacker, &10_001);
+    usdc.mint(&victim, &5_000);
+    assert_eq!(lumen.deposit(&attacker, &Tranche::Junior, &1, &0, &None), 1);
+    usdc.transfer(&attacker, &lumen.address, &10_000);
+
+    // The donation is not pool assets, the price does not move
+    assert_eq!(lumen.get_nav(), 1);
+    assert_eq!(lumen.get_share_price(&Tranche::Junior), 10_000_000);
+    assert_eq!(lumen.deposit(&victim, &Tranche::Junior, &5_000, &0, &None), 5_000);
+
+    assert_eq!(lumen.withdraw(&victim, &Tranche::Junior, &5_000, &0, &None), 5_000);
+    assert_eq!(lumen.withdraw(&attacker, &Tranche::Junior, &1, &0, &None), 1);
+    assert_eq!(usdc.balance(&attacker), 1);
+
+    // An early exit fee stays in the pool like a donation. Leaving a single
+    // share behind must not let the attacker price it above the next deposit
+    apply_change(&e, &lumen, ParamChange::LockupTerms(30 * 86_400, Some(5_000)));
+    usdc.mint(&attacker, &20_000);
+    usdc.mint(&victim, &10_000);
+    assert_eq!(lumen.deposit(&attacker, &Tranche::Junior, &20_001, &0, &None), 20_001);
+    assert_eq!(lumen.withdraw(&attacker, &Tranche::Junior, &20_000, &0, &None), 10_000);
+    // The fee is spread over the virtual shares, the one left is worth little
+    assert_eq!(lumen.get_user_share_balance(&attacker, &Tranche::Junior), 10);
+    lumen.deposit(&victim, &Tranche::Junior, &15_000, &0, &None);
+    let victim_assets = lumen.get_user_share_balance(&victim, &Tranche::Junior);
+    assert!((15_000 - 11..=15_000).contains(&victim_assets));
+}
+
+#[test]
+fn test_skim_and_balance_check() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+    let treasury = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &271u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+    let request_id = lumen.request_withdraw(&depositor, &Tranche::Junior, &500);
+
+    // Repayment sends the insurance cut out and funds the queued withdrawal
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    usdc.mint(&borrower, &80);
+    lumen.repay_loan(&loan_id);
+    assert_eq!(lumen.get_withdraw_reserve(), 518);
+    assert_eq!(lumen.get_idle_assets(), 1000 + 72 - 518);
+
+    let check = lumen.check_balances();
+    assert_eq!(check.balance, check.idle_assets + check.withdraw_reserve);
+    assert_eq!(check.surplus, 0);
+    assert_eq!(lumen.skim(&admin, &treasury), 0);
+
+    // Stray USDC is tracked as surplus, never as pool assets
+    usdc.mint(&admin, &250);
+    usdc.transfer(&admin, &lumen.address, &250);
+    assert_eq!(lumen.check_balances().surplus, 250);
+    assert_eq!(lumen.get_nav(), 554);
+
+    assert_eq!(lumen.skim(&admin, &treasury), 250);
+    assert_eq!(usdc.balance(&treasury), 250);
+    assert_eq!(lumen.check_balances().surplus, 0);
+    assert_eq!(lumen.claim_withdraw(&request_id), 518);
+    assert_eq!(usdc.balance(&lumen.address), lumen.get_idle_assets());
+}
+
+#[test]
+fn test_admin_transfer_and_renounce() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let new_admin = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    assert_eq!(lumen.get_admin(), admin);
+    assert_eq!(lumen.pending_admin(), None);
+    assert_eq!(lumen.try_accept_admin(), Err(Ok(Error::NoPendingAdmin)));
+
+    // The proposed admin has to accept before the role moves
+    lumen.propose_admin(&new_admin);
+    assert_eq!(lumen.pending_admin(), Some(new_admin.clone()));
+    assert_eq!(lumen.get_admin(), admin);
+
+    lumen.accept_admin();
+    assert_eq!(e.auths()[0].0, new_admin);
+    assert_eq!(lumen.get_admin(), new_admin);
+    assert_eq!(lumen.pending_admin(), None);
+
+    lumen.whitelist(&new_admin, &borrower);
+    assert_eq!(e.auths()[0].0, new_admin);
+
+    // Once renounced, admin-only entrypoints stay closed and the pool cannot
+    // be initialized again
+    lumen.renounce_admin();
+    assert_eq!(lumen.try_get_admin(), Err(Ok(Error::AdminRenounced)));
+    assert_eq!(lumen.try_whitelist(&new_admin, &borrower), Err(Ok(Error::MissingRole)));
+    assert_eq!(lumen.try_propose_admin(&admin), Err(Ok(Error::AdminRenounced)));
+    assert_eq!(
+        lumen.try_initialize(&install_token_wasm(&e), &usdc.address, &admin, &insurance),
+        Err(Ok(Error::AlreadyInitialized))
+    );
+}
+
+#[test]
+fn test_roles() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let underwriter = Address::generate(&e);
+    let kyc_operator = Address::generate(&e);
+    let risk_manager = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    lumen.grant_role(&Role::Underwriter, &underwriter);
+    lumen.grant_role(&Role::KycOperator, &kyc_operator);
+    lumen.grant_role(&Role::RiskManager, &risk_manager);
+    assert!(lumen.has_role(&Role::Underwriter, &underwriter));
+    assert!(!lumen.has_role(&Role::KycOperator, &underwriter));
+
+    // Each role only unlocks its own duties
+    assert_eq!(lumen.try_whitelist(&underwriter, &borrower), Err(Ok(Error::MissingRole)));
+    lumen.whitelist(&kyc_operator, &borrower);
+    assert_eq!(e.auths()[0].0, kyc_operator);
+
+    let loan_id = lumen.request_loan(&borrower, &1000, &271u32, &REPAYMENT_DATE);
+    assert_eq!(lumen.try_approve_loan(&kyc_operator, &loan_id, &APR_BPS), Err(Ok(Error::MissingRole)));
+    lumen.approve_loan(&underwriter, &loan_id, &APR_BPS);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Approved);
+
+    assert_eq!(lumen.try_mark_default(&underwriter, &loan_id), Err(Ok(Error::MissingRole)));
+    assert_eq!(lumen.try_mark_default(&risk_manager, &loan_id), Err(Ok(Error::DefaultWindowNotElapsed)));
+
+    // The admin keeps every role
+    lumen.reject_loan(&admin, &loan_id);
+
+    lumen.revoke_role(&Role::Underwriter, &underwriter);
+    assert!(!lumen.has_role(&Role::Underwriter, &underwriter));
+    let loan_id = lumen.request_loan(&borrower, &1000, &272u32, &REPAYMENT_DATE);
+    assert_eq!(lumen.try_approve_loan(&underwriter, &loan_id, &APR_BPS), Err(Ok(Error::MissingRole)));
+}
+
+#[test]
+fn test_pause_and_circuit_breakers() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let pauser = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    usdc.mint(&depositor, &2000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+    let loan_id = lumen.request_loan(&borrower, &800, &271u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    lumen.claim_loan(&loan_id);
+
+    assert_eq!(lumen.try_pause(&pauser), Err(Ok(Error::MissingRole)));
+    lumen.grant_role(&Role::Pauser, &pauser);
+
+    // A single switch leaves the rest of the pool running
+    lumen.set_operation_paused(&pauser, &Operation::Deposit, &true);
+    assert!(lumen.is_operation_paused(&Operation::Deposit));
+    assert!(!lumen.is_paused());
+    assert_eq!(
+        lumen.try_deposit(&depositor, &Tranche::Junior, &100, &0, &None),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(lumen.max_deposit(&depositor, &Tranche::Junior), 0);
+    assert!(lumen.max_withdraw(&depositor, &Tranche::Junior) > 0);
+    lumen.withdraw(&depositor, &Tranche::Junior, &50, &0, &None);
+
+    // The global pause stops everything but repayments
+    lumen.pause(&pauser);
+    assert_eq!(
+        lumen.try_withdraw(&depositor, &Tranche::Junior, &50, &0, &None),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(lumen.max_withdraw(&depositor, &Tranche::Junior), 0);
+    assert_eq!(
+        lumen.try_request_withdraw(&depositor, &Tranche::Junior, &50),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(
+        lumen.try_request_loan(&borrower, &800, &272u32, &REPAYMENT_DATE),
+        Err(Ok(Error::Paused))
+    );
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &APR_BPS), Err(Ok(Error::Paused)));
+    assert_eq!(lumen.try_claim_loan(&loan_id), Err(Ok(Error::Paused)));
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = REPAYMENT_DATE;
+    });
+    usdc.mint(&borrower, &80);
+    lumen.repay_loan(&loan_id);
+    assert_eq!(lumen.get_loan_details(&loan_id).status, LoanStatus::Repaid);
+
+    // Unpausing keeps the deposit switch in place
+    lumen.unpause(&pauser);
+    lumen.withdraw(&depositor, &Tranche::Junior, &50, &0, &None);
+    assert!(lumen.is_operation_paused(&Operation::Deposit));
+    lumen.set_operation_paused(&pauser, &Operation::Deposit, &false);
+    assert_eq!(lumen.max_deposit(&depositor, &Tranche::Junior), i128::MAX);
+    lumen.deposit(&depositor, &Tranche::Junior, &100, &0, &None);
+}
+
+// `loan` as stored before endorsements were recorded
+fn loan_details_v1(loan: LoanDetails) -> LoanDetailsV1 {
+    LoanDetailsV1 {
+        who: loan.who,
+        inv_no: loan.inv_no,
+        apr_bps: loan.apr_bps,
+        invoice_amount: loan.invoice_amount,
+        loan_amount: loan.loan_amount,
+        repayment_date: loan.repayment_date,
+        released_at: loan.released_at,
+        status: loan.status,
+        grace_period: loan.grace_period,
+        penalty_apr_bps: loan.penalty_apr_bps,
+        installments: loan.installments,
+        amount_repaid: loan.amount_repaid,
+        penalty_paid: loan.penalty_paid,
+        fee_paid: loan.fee_paid,
+        penalty_accrued: loan.penalty_accrued,
+        penalty_accrued_at: loan.penalty_accrued_at,
+    }
+}
+
+#[test]
+fn test_migrate_from_schema_1() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    assert_eq!(lumen.get_schema_version(), SCHEMA_VERSION);
+    assert_eq!(lumen.try_migrate(), Err(Ok(Error::AlreadyMigrated)));
+
+    usdc.mint(&depositor, &1000);
+    lumen.deposit(&depositor, &Tranche::Junior, &1000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+    for inv_no in 0..60u32 {
+        lumen.request_loan(&borrower, &100, &inv_no, &REPAYMENT_DATE);
+    }
+    lumen.approve_loan(&admin, &1, &APR_BPS);
+
+    // A pool without a stored version cannot be migrated
+    e.as_contract(&lumen.address, || {
+        e.storage().instance().remove(&DataKey::SchemaVersion);
+    });
+    assert_eq!(lumen.try_migrate(), Err(Ok(Error::SchemaNotSupported)));
+
+    // Storage as written under schema 1
+    e.as_contract(&lumen.address, || {
+        e.storage().instance().set(&DataKey::SchemaVersion, &1u32);
+        e.storage().instance().remove(&DataKey::Config);
+        e.storage().instance().set(&DataKey::GracePeriod, &(5 * 86_400u64));
+        e.storage().instance().set(&DataKey::PenaltyRate, &3_650i128);
+        for loan_id in 1..=60u64 {
+            let key = AdminDataKey::LOAN(loan_id);
+            let loan: LoanDetails = e.storage().persistent().get(&key).unwrap();
+            e.storage().persistent().set(&key, &loan_details_v1(loan));
+        }
+    });
+    assert_eq!(lumen.get_schema_version(), 1);
+    assert_eq!(
+        lumen.try_deposit(&depositor, &Tranche::Junior, &100, &0, &None),
+        Err(Ok(Error::MigrationRequired))
+    );
+    assert_eq!(lumen.try_claim_loan(&1), Err(Ok(Error::MigrationRequired)));
+    assert_eq!(lumen.try_get_loan_details(&1), Err(Ok(Error::MigrationRequired)));
+    assert_eq!(lumen.try_get_amount_due(&1), Err(Ok(Error::MigrationRequired)));
+    assert_eq!(lumen.try_get_loan_id(&borrower, &0), Err(Ok(Error::MigrationRequired)));
+
+    // Loans are converted in batches before the version moves on
+    assert!(!lumen.migrate());
+    assert_eq!(lumen.get_schema_version(), 1);
+    assert!(lumen.migrate());
+    assert_eq!(lumen.get_schema_version(), SCHEMA_VERSION);
+
+    e.as_contract(&lumen.address, || {
+        assert!(!e.storage().instance().has(&DataKey::GracePeriod));
+        assert!(!e.storage().instance().has(&DataKey::PenaltyRate));
+        assert!(!e.storage().instance().has(&DataKey::MigrationCursor));
+    });
+    assert_eq!(lumen.get_late_payment_terms(), (5 * 86_400, 3_650));
+    let loan = lumen.get_loan_details(&1);
+    assert_eq!(loan.status, LoanStatus::Approved);
+    assert_eq!(loan.endorsements.len(), 0);
+    assert_eq!((loan.insurance_fee_bps, loan.protocol_fee_bps), (1_000, 0));
+    assert_eq!(lumen.get_loan_details(&60).status, LoanStatus::Requested);
+    assert_eq!(lumen.get_nav(), 1000);
+    lumen.claim_loan(&1);
+}
+
+#[test]
+fn test_upgrade() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+    let token_wasm_hash = install_token_wasm(&e);
+
+    let lumen = create_lumenfinance_contract(&e, &token_wasm_hash, &usdc.address, &admin, &insurance);
+
+    // Upgrades go through the timelock like any other parameter change
+    let change_id = lumen.queue_change(&ParamChange::Upgrade(token_wasm_hash.clone()));
+    assert_eq!(lumen.try_execute_change(&change_id), Err(Ok(Error::TimelockNotElapsed)));
+    let delay = lumen.get_timelock_delay();
+    e.ledger().with_mut(|li| {
+        li.timestamp = delay;
+    });
+    lumen.execute_change(&change_id);
+    assert_eq!(
+        e.auths(),
+        std::vec![(
+            admin.clone(),
+            AuthorizedInvocation {
+                function: AuthorizedFunction::Contract((
+                    lumen.address.clone(),
+                    Symbol::new(&e, "execute_change"),
+                    (change_id,).into_val(&e),
+                )),
+                sub_invocations: std::vec![]
+            }
+        )]
+    );
+
+    // The pool address now runs the new code
+    let upgraded = token::Client::new(&e, &lumen.address);
+    upgraded.initialize(&admin, &7u32, &"Upgraded".into_val(&e), &"UPG".into_val(&e));
+    assert_eq!(upgraded.decimals(), 7);
+}
+
+#[test]
+fn test_timelocked_changes() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let new_insurance = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let delay = lumen.get_timelock_delay();
+    assert_eq!(delay, 2 * 86_400);
+
+    let change_id = lumen.queue_change(&ParamChange::InsuranceAddress(new_insurance.clone()));
+    let queued = lumen.get_queued_change(&change_id);
+    assert_eq!(queued.eta, delay);
+    assert_eq!(queued.status, ChangeStatus::Queued);
+    let events = e.events().all();
+    assert_eq!(
+        events.slice(events.len() - 1..),
+        vec![
+            &e,
+            (
+                lumen.address.clone(),
+                (AdminDataKey::CHANGE(change_id), Symbol::new(&e, "change_queued"), ChangeStatus::Queued).into_val(&e),
+                queued.into_val(&e),
+            )
+        ]
+    );
+
+    // Nothing changes until the delay has passed
+    assert_eq!(lumen.try_execute_change(&change_id), Err(Ok(Error::TimelockNotElapsed)));
+    assert_eq!(lumen.get_insurance_address(), insurance);
+
+    e.ledger().with_mut(|li| {
+        li.timestamp = delay;
+    });
+    lumen.execute_change(&change_id);
+    assert_eq!(lumen.get_insurance_address(), new_insurance);
+    assert_eq!(lumen.get_queued_change(&change_id).status, ChangeStatus::Executed);
+    assert_eq!(lumen.try_execute_change(&change_id), Err(Ok(Error::ChangeNotQueued)));
+
+    // A cancelled change can never run
+    let change_id = lumen.queue_change(&ParamChange::InsuranceAddress(insurance.clone()));
+    lumen.cancel_change(&change_id);
+    e.ledger().with_mut(|li| {
+        li.timestamp = 2 * delay;
+    });
+    assert_eq!(lumen.try_execute_change(&change_id), Err(Ok(Error::ChangeNotQueued)));
+    assert_eq!(lumen.try_cancel_change(&change_id), Err(Ok(Error::ChangeNotQueued)));
+    assert_eq!(lumen.try_get_queued_change(&99), Err(Ok(Error::ChangeNotFound)));
+
+    // A new delay only applies to changes queued after it executes
+    let change_id = lumen.queue_change(&ParamChange::TimelockDelay(7 * 86_400));
+    let pending_id = lumen.queue_change(&ParamChange::InsuranceAddress(insurance.clone()));
+    e.ledger().with_mut(|li| {
+        li.timestamp = 3 * delay;
+    });
+    lumen.execute_change(&change_id);
+    assert_eq!(lumen.get_timelock_delay(), 7 * 86_400);
+    assert_eq!(lumen.queue_change(&ParamChange::InsuranceAddress(insurance.clone())), pending_id + 1);
+    assert_eq!(lumen.get_queued_change(&(pending_id + 1)).eta, 3 * delay + 7 * 86_400);
+    lumen.execute_change(&pending_id);
+    assert_eq!(lumen.get_insurance_address(), insurance);
+
+    // Pool terms wait out the delay too and are checked when queued
+    assert_eq!(
+        lumen.try_queue_change(&ParamChange::TrancheTerms(1_000, 10_001)),
+        Err(Ok(Error::InvalidAmount))
+    );
+    assert_eq!(
+        lumen.try_queue_change(&ParamChange::LockupTerms(86_400, Some(10_001))),
+        Err(Ok(Error::InvalidAmount))
+    );
+    let change_id = lumen.queue_change(&ParamChange::LockupTerms(86_400, Some(200)));
+    let window_id = lumen.queue_change(&ParamChange::DefaultWindow(86_400));
+    assert_eq!(lumen.try_execute_change(&change_id), Err(Ok(Error::TimelockNotElapsed)));
+    assert_eq!(lumen.get_lockup_terms(), (0, None));
+    e.ledger().with_mut(|li| {
+        li.timestamp = 3 * delay + 14 * 86_400;
+    });
+    lumen.execute_change(&change_id);
+    lumen.execute_change(&window_id);
+    assert_eq!(lumen.get_lockup_terms(), (86_400, Some(200)));
+    assert_eq!(lumen.get_default_window(), 86_400);
+}
+
+#[test]
+fn test_loan_endorsements() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let underwriter = Address::generate(&e);
+    let underwriter2 = Address::generate(&e);
+    let underwriter3 = Address::generate(&e);
+    let borrower = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    for underwriter in [&underwriter, &underwriter2, &underwriter3] {
+        lumen.grant_role(&Role::Underwriter, underwriter);
+    }
+    // Invoices above 10_000 need two of the three underwriters
+    assert_eq!(
+        lumen.try_queue_change(&ParamChange::EndorsementTerms(10_000, 0)),
+        Err(Ok(Error::InvalidAmount))
+    );
+    apply_change(&e, &lumen, ParamChange::EndorsementTerms(10_000, 2));
+    assert_eq!(lumen.get_endorsement_terms(), (10_000, 2));
+
+    lumen.whitelist(&admin, &borrower);
+    let small_loan_id = lumen.request_loan(&borrower, &10_000, &271u32, &REPAYMENT_DATE);
+    lumen.approve_loan(&underwriter, &small_loan_id, &APR_BPS);
+    let loan = lumen.get_loan_details(&small_loan_id);
+    assert_eq!(loan.status, LoanStatus::Approved);
+    assert_eq!(loan.endorsements, vec![&e, underwriter.clone()]);
+
+    let loan_id = lumen.request_loan(&borrower, &50_000, &272u32, &REPAYMENT_DATE);
+    assert_eq!(lumen.try_endorse_loan(&underwriter2, &loan_id), Err(Ok(Error::TermsNotProposed)));
+
+    // The first approval only proposes the terms
+    lumen.approve_loan(&underwriter, &loan_id, &APR_BPS);
+    let loan = lumen.get_loan_details(&loan_id);
+    assert_eq!(loan.status, LoanStatus::Requested);
+    assert_eq!(loan.apr_bps, APR_BPS);
+    assert_eq!(lumen.try_claim_loan(&loan_id), Err(Ok(Error::NotApproved)));
+    assert_eq!(lumen.try_endorse_loan(&underwriter, &loan_id), Err(Ok(Error::AlreadyEndorsed)));
+    assert_eq!(lumen.try_endorse_loan(&borrower, &loan_id), Err(Ok(Error::MissingRole)));
+
+    // New terms start the endorsements over
+    lumen.approve_loan(&underwriter2, &loan_id, &(APR_BPS / 2));
+    assert_eq!(lumen.get_loan_details(&loan_id).endorsements, vec![&e, underwriter2.clone()]);
+
+    lumen.endorse_loan(&underwriter3, &loan_id);
+    let loan = lumen.get_loan_details(&loan_id);
+    assert_eq!(loan.status, LoanStatus::Approved);
+    assert_eq!(loan.apr_bps, APR_BPS / 2);
+    assert_eq!(loan.endorsements, vec![&e, underwriter2.clone(), underwriter3.clone()]);
+    assert_eq!(lumen.try_endorse_loan(&underwriter, &loan_id), Err(Ok(Error::AlreadyApproved)));
+}
+
+#[test]
+fn test_pool_config() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let insurance = Address::generate(&e);
+    let depositor = Address::generate(&e);
+    let borrower = Address::generate(&e);
+    let treasury = Address::generate(&e);
+
+    let usdc = create_token_contract(&e, &admin);
+
+    let lumen = create_lumenfinance_contract(
+        &e,
+        &install_token_wasm(&e),
+        &usdc.address,
+        &admin,
+        &insurance,
+    );
+
+    let mut config = lumen.get_config();
+    assert_eq!(config.insurance_fee_bps, lumen.get_insurance_fee_rate());
+    assert_eq!(config.max_apr_bps, MAX_APR_BPS);
+
+    // Cuts cannot take more than the whole fee
+    let invalid = PoolConfig { insurance_fee_bps: 6_000, protocol_fee_bps: 5_000, ..config.clone() };
+    assert_eq!(lumen.try_queue_change(&ParamChange::PoolConfig(invalid)), Err(Ok(Error::InvalidConfig)));
+    let invalid = PoolConfig { min_tenor: 10, max_tenor: 5, ..config.clone() };
+    assert_eq!(lumen.try_queue_change(&ParamChange::PoolConfig(invalid)), Err(Ok(Error::InvalidConfig)));
+    // The late penalty is capped like any other APR
+    let invalid = PoolConfig { penalty_apr_bps: MAX_APR_BPS + 1, ..config.clone() };
+    assert_eq!(lumen.try_queue_change(&ParamChange::PoolConfig(invalid)), Err(Ok(Error::InvalidConfig)));
+
+    config.insurance_fee_bps = 500;
+    config.protocol_fee_bps = 1_000;
+    config.max_apr_bps = 6_000;
+    config.min_tenor = 30 * 86_400;
+    config.max_tenor = 120 * 86_400;
+    config.max_advance_bps = 9_500;
+    let change_id = lumen.queue_change(&ParamChange::PoolConfig(config.clone()));
+    e.ledger().with_mut(|li| {
+        li.timestamp = 2 * 86_400;
+    });
+    lumen.execute_change(&change_id);
+    assert_eq!(lumen.get_config(), config);
+    assert_eq!(lumen.get_insurance_fee_rate(), 500);
+
+    usdc.mint(&depositor, &10_000);
+    lumen.deposit(&depositor, &Tranche::Junior, &10_000, &0, &None);
+    lumen.whitelist(&admin, &borrower);
+
+    // Tenor, APR and advance rate limits
+    let start = 2 * 86_400;
+    assert_eq!(
+        lumen.try_request_loan(&borrower, &1000, &1u32, &(start + 29 * 86_400)),
+        Err(Ok(Error::TenorOutOfRange))
+    );
+    assert_eq!(
+        lumen.try_request_loan(&borrower, &1000, &1u32, &(start + 121 * 86_400)),
+        Err(Ok(Error::TenorOutOfRange))
+    );
+    let loan_id = lumen.request_loan(&borrower, &1000, &1u32, &(start + REPAYMENT_DATE));
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &6_001), Err(Ok(Error::FeeRateTooHigh)));
+    assert_eq!(lumen.try_approve_loan(&admin, &loan_id, &(APR_BPS / 5)), Err(Ok(Error::AdvanceRateTooHigh)));
+
+    // Loans keep the cuts they were approved with
+    lumen.approve_loan(&admin, &loan_id, &APR_BPS);
+    let loan = lumen.get_loan_details(&loan_id);
+    assert_eq!((loan.insurance_fee_bps, loan.protocol_fee_bps), (500, 1_000));
+    lumen.claim_loan(&loan_id);
+    assert_eq!(usdc.balance(&borrower), 900);
+
+    // 100 fee: 5 to insurance, 10 to the protocol, 85 to LPs
+    e.ledger().with_mut(|li| {
+        li.timestamp = start + REPAYMENT_DATE;
+    });
+    usdc.mint(&borrower, &100);
+    assert_eq!(lumen.repay_loan(&loan_id), (1000, 5));
+    assert_eq!(lumen.get_protocol_fees(), 10);
+    assert_eq!(lumen.get_nav(), 10_085);
+    assert_eq!(lumen.check_balances().surplus, 0);
+
+    assert_eq!(lumen.try_collect_protocol_fees(&borrower, &treasury), Err(Ok(Error::MissingRole)));
+    assert_eq!(lumen.collect_protocol_fees(&admin, &treasury), 10);
+    assert_eq!(usdc.balance(&treasury), 10);
+    assert_eq!(lumen.get_protocol_fees(), 0);
+    assert_eq!(lumen.check_balances().surplus, 0);
 }
@@ -0,0 +1,82 @@
+use soroban_sdk::{contracttype, Address, BytesN, Env};
+
+use crate::{
+    AdminDataKey, DataKey, Error, PoolConfig, DEFAULT_TIMELOCK_DELAY, LOAN_BUMP_AMOUNT, LOAN_LIFETIME_THRESHOLD,
+};
+
+// Parameter changes that only take effect once the timelock delay has passed
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParamChange {
+    // Replace the contract code, storage is converted by `migrate` afterwards
+    Upgrade(BytesN<32>),
+    InsuranceAddress(Address),
+    // Delay applied to changes queued after this one executes
+    TimelockDelay(u64),
+    PoolConfig(PoolConfig),
+    // Lockup period after each deposit and the early exit fee in basis points
+    LockupTerms(u64, Option<i128>),
+    // Senior APR cap and the minimum junior share of NAV, in basis points
+    TrancheTerms(i128, i128),
+    // Invoice amount above which loans need endorsing, and by how many underwriters
+    EndorsementTerms(i128, u32),
+    DefaultWindow(u64),
+}
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum ChangeStatus {
+    Queued = 0,
+    Executed = 1,
+    Cancelled = 2,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueuedChange {
+    pub change: ParamChange,
+    pub queued_at: u64,
+    // Earliest time the change can be executed
+    pub eta: u64,
+    pub status: ChangeStatus,
+}
+
+impl QueuedChange {
+    pub fn check_executable(&self, now: u64) -> Result<(), Error> {
+        if self.status != ChangeStatus::Queued {
+            return Err(Error::ChangeNotQueued);
+        }
+        if now < self.eta {
+            return Err(Error::TimelockNotElapsed);
+        }
+        Ok(())
+    }
+}
+
+pub fn get_timelock_delay(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::TimelockDelay).unwrap_or(DEFAULT_TIMELOCK_DELAY)
+}
+
+pub fn put_timelock_delay(e: &Env, delay: u64) {
+    e.storage().instance().set(&DataKey::TimelockDelay, &delay);
+}
+
+pub fn read_change(e: &Env, change_id: u64) -> Result<QueuedChange, Error> {
+    let key = AdminDataKey::CHANGE(change_id);
+    let change = e.storage().persistent().get(&key).ok_or(Error::ChangeNotFound)?;
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    Ok(change)
+}
+
+pub fn write_change(e: &Env, change_id: u64, change: &QueuedChange) {
+    let key = AdminDataKey::CHANGE(change_id);
+    e.storage().persistent().set(&key, change);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+pub fn next_change_id(e: &Env) -> u64 {
+    let change_id: u64 = e.storage().instance().get(&DataKey::NextChangeId).unwrap_or(1);
+    e.storage().instance().set(&DataKey::NextChangeId, &(change_id + 1));
+    change_id
+}
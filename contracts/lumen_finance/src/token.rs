@@ -1,6 +1,8 @@
 #![allow(unused)]
 use soroban_sdk::{xdr::ToXdr, Address, Bytes, BytesN, Env};
 
+use crate::Tranche;
+
 soroban_sdk::contractimport!(
     file = "token/soroban_token_contract.wasm"
 );
@@ -9,9 +11,11 @@ pub fn create_contract(
     e: &Env,
     token_wasm_hash: BytesN<32>,
     usdc: &Address,
+    tranche: Tranche,
 ) -> Address {
     let mut salt = Bytes::new(e);
     salt.append(&usdc.to_xdr(e));
+    salt.push_back(tranche as u8);
     let salt = e.crypto().sha256(&salt);
     e.deployer()
         .with_current_contract(salt)
@@ -0,0 +1,68 @@
+use soroban_sdk::{contracttype, Env};
+
+use crate::{DataKey, BPS_DENOMINATOR, SECONDS_PER_YEAR};
+
+// Fixed-point scale of the senior index, USDC owed per senior share
+pub(crate) const SENIOR_INDEX_SCALE: i128 = 1_000_000_000;
+
+// The senior tranche is paid first, its yield capped at the senior APR. The
+// junior tranche takes the first loss and whatever yield is left.
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum Tranche {
+    Senior = 0,
+    Junior = 1,
+}
+
+impl Tranche {
+    pub(crate) fn token_share_key(self) -> DataKey {
+        match self {
+            Tranche::Senior => DataKey::SeniorTokenShare,
+            Tranche::Junior => DataKey::TokenShare,
+        }
+    }
+
+    pub(crate) fn total_shares_key(self) -> DataKey {
+        match self {
+            Tranche::Senior => DataKey::SeniorTotalShares,
+            Tranche::Junior => DataKey::TotalShares,
+        }
+    }
+
+    pub(crate) fn queued_shares_key(self) -> DataKey {
+        match self {
+            Tranche::Senior => DataKey::SeniorQueuedShares,
+            Tranche::Junior => DataKey::JuniorQueuedShares,
+        }
+    }
+}
+
+fn get_senior_index(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::SeniorIndex).unwrap_or(SENIOR_INDEX_SCALE)
+}
+
+fn get_senior_index_at(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::SeniorIndexAt).unwrap_or(e.ledger().timestamp())
+}
+
+// What each senior share is owed now, growing at the senior APR since the
+// last checkpoint
+pub fn senior_index_now(e: &Env, senior_apr_bps: i128) -> i128 {
+    let elapsed = e.ledger().timestamp().saturating_sub(get_senior_index_at(e)) as i128;
+    let index = get_senior_index(e);
+    index + index * senior_apr_bps * elapsed / (SECONDS_PER_YEAR * BPS_DENOMINATOR)
+}
+
+// Checkpoint the index before the senior APR changes
+pub fn checkpoint_senior_index(e: &Env, senior_apr_bps: i128) {
+    e.storage().instance().set(&DataKey::SeniorIndex, &senior_index_now(e, senior_apr_bps));
+    e.storage().instance().set(&DataKey::SeniorIndexAt, &e.ledger().timestamp());
+}
+
+// Share `amount` left behind in the pool among the remaining senior shares
+pub fn credit_senior_index(e: &Env, senior_apr_bps: i128, amount: i128, senior_shares: i128) {
+    checkpoint_senior_index(e, senior_apr_bps);
+    let index = get_senior_index(e) + amount * SENIOR_INDEX_SCALE / senior_shares;
+    e.storage().instance().set(&DataKey::SeniorIndex, &index);
+}
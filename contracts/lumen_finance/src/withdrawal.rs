@@ -0,0 +1,97 @@
+use soroban_sdk::{contracttype, Address, Env, Vec};
+
+use crate::{AdminDataKey, DataKey, Error, Lockup, Tranche, LOAN_BUMP_AMOUNT, LOAN_LIFETIME_THRESHOLD};
+
+#[contracttype]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum WithdrawStatus {
+    // Shares escrowed, waiting for liquidity
+    Pending = 0,
+    // Shares burnt and the USDC set aside for the owner to claim
+    Funded = 1,
+    Claimed = 2,
+    Cancelled = 3,
+}
+
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WithdrawRequest {
+    pub owner: Address,
+    pub tranche: Tranche,
+    pub share_amount: i128,
+    // USDC owed, fixed at the share price when the request is funded and net
+    // of any early exit fee
+    pub amount: i128,
+    // Shares taken out of the owner's lockups, they pay the early exit fee and
+    // go back into their lockups if the request is cancelled
+    pub locked_shares: i128,
+    pub lockups: Vec<Lockup>,
+    pub exit_fee_bps: i128,
+    pub requested_at: u64,
+    pub status: WithdrawStatus,
+}
+
+pub fn read_withdraw_request(e: &Env, request_id: u64) -> Result<WithdrawRequest, Error> {
+    let key = AdminDataKey::WITHDRAWAL(request_id);
+    let request = e.storage().persistent().get(&key).ok_or(Error::WithdrawalNotFound)?;
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+    Ok(request)
+}
+
+pub fn write_withdraw_request(e: &Env, request_id: u64, request: &WithdrawRequest) {
+    let key = AdminDataKey::WITHDRAWAL(request_id);
+    e.storage().persistent().set(&key, request);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+// Requests are queued under monotonic ids starting at 1
+pub fn next_withdraw_id(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::NextWithdrawId).unwrap_or(1)
+}
+
+pub fn register_withdraw_id(e: &Env) -> u64 {
+    let request_id = next_withdraw_id(e);
+    e.storage().instance().set(&DataKey::NextWithdrawId, &(request_id + 1));
+    request_id
+}
+
+// Oldest request that may still be pending
+pub fn get_queue_head(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::WithdrawQueueHead).unwrap_or(1)
+}
+
+pub fn put_queue_head(e: &Env, request_id: u64) {
+    e.storage().instance().set(&DataKey::WithdrawQueueHead, &request_id)
+}
+
+// Junior requests held back by the minimum coverage are parked in their own
+// queue, in request order, so they do not hold up the requests behind them.
+// Parked slots count up from 1 like request ids.
+pub fn get_parked_head(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::ParkedHead).unwrap_or(1)
+}
+
+pub fn put_parked_head(e: &Env, slot: u64) {
+    e.storage().instance().set(&DataKey::ParkedHead, &slot)
+}
+
+pub fn next_parked_slot(e: &Env) -> u64 {
+    e.storage().instance().get(&DataKey::NextParkedSlot).unwrap_or(1)
+}
+
+pub fn park_request(e: &Env, request_id: u64) {
+    let slot = next_parked_slot(e);
+    e.storage().instance().set(&DataKey::NextParkedSlot, &(slot + 1));
+    let key = AdminDataKey::PARKED(slot);
+    e.storage().persistent().set(&key, &request_id);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+pub fn read_parked_request(e: &Env, slot: u64) -> Option<u64> {
+    e.storage().persistent().get(&AdminDataKey::PARKED(slot))
+}
+
+pub fn remove_parked_request(e: &Env, slot: u64) {
+    e.storage().persistent().remove(&AdminDataKey::PARKED(slot))
+}
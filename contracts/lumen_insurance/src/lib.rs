@@ -0,0 +1,211 @@
+#![no_std]
+
+mod test;
+mod error;
+
+use soroban_sdk::{contract, contractimpl, contracttype, token, Address, Env, Symbol};
+pub use error::Error;
+
+pub(crate) const BPS_DENOMINATOR: i128 = 10_000;
+
+pub(crate) const DAY_IN_LEDGERS: u32 = 17280;
+pub(crate) const INSTANCE_BUMP_AMOUNT: u32 = 30 * DAY_IN_LEDGERS;
+pub(crate) const INSTANCE_LIFETIME_THRESHOLD: u32 = INSTANCE_BUMP_AMOUNT - DAY_IN_LEDGERS;
+// Per-loan coverage and claims, kept as long as the pool keeps its loans
+pub(crate) const LOAN_BUMP_AMOUNT: u32 = 120 * DAY_IN_LEDGERS;
+pub(crate) const LOAN_LIFETIME_THRESHOLD: u32 = LOAN_BUMP_AMOUNT - 7 * DAY_IN_LEDGERS;
+
+#[derive(Clone)]
+#[contracttype]
+pub enum DataKey {
+    Admin,
+    Usdc,
+    // The LumenFinance pool this reserve insures
+    Pool,
+    Reserve,
+    DefaultCoverage,
+    Coverage(u64),
+    Claimed(u64),
+}
+
+fn get_admin(e: &Env) -> Address {
+    e.storage().instance().get(&DataKey::Admin).unwrap()
+}
+
+fn get_usdc(e: &Env) -> Address {
+    e.storage().instance().get(&DataKey::Usdc).unwrap()
+}
+
+fn get_pool(e: &Env) -> Address {
+    e.storage().instance().get(&DataKey::Pool).unwrap()
+}
+
+fn get_reserve(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::Reserve).unwrap_or(0)
+}
+
+fn get_default_coverage(e: &Env) -> i128 {
+    e.storage().instance().get(&DataKey::DefaultCoverage).unwrap()
+}
+
+fn put_reserve(e: &Env, amount: i128) {
+    e.storage().instance().set(&DataKey::Reserve, &amount)
+}
+
+fn put_default_coverage(e: &Env, coverage_bps: i128) {
+    e.storage().instance().set(&DataKey::DefaultCoverage, &coverage_bps)
+}
+
+// Share of a loan's loss the reserve pays, the default unless set for the loan
+fn get_coverage(e: &Env, loan_id: u64) -> i128 {
+    let key = DataKey::Coverage(loan_id);
+    match e.storage().persistent().get(&key) {
+        Some(coverage_bps) => {
+            e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+            coverage_bps
+        }
+        None => get_default_coverage(e),
+    }
+}
+
+fn put_coverage(e: &Env, loan_id: u64, coverage_bps: i128) {
+    let key = DataKey::Coverage(loan_id);
+    e.storage().persistent().set(&key, &coverage_bps);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+fn is_claimed(e: &Env, loan_id: u64) -> bool {
+    e.storage().persistent().has(&DataKey::Claimed(loan_id))
+}
+
+fn put_claimed(e: &Env, loan_id: u64, paid: i128) {
+    let key = DataKey::Claimed(loan_id);
+    e.storage().persistent().set(&key, &paid);
+    e.storage().persistent().extend_ttl(&key, LOAN_LIFETIME_THRESHOLD, LOAN_BUMP_AMOUNT);
+}
+
+fn check_initialized(e: &Env) -> Result<(), Error> {
+    if !e.storage().instance().has(&DataKey::Admin) {
+        return Err(Error::NotInitialized);
+    }
+    e.storage().instance().extend_ttl(INSTANCE_LIFETIME_THRESHOLD, INSTANCE_BUMP_AMOUNT);
+    Ok(())
+}
+
+fn check_amount(amount: i128) -> Result<(), Error> {
+    if amount <= 0 {
+        return Err(Error::InvalidAmount);
+    }
+    Ok(())
+}
+
+fn check_coverage(coverage_bps: i128) -> Result<(), Error> {
+    if !(0..=BPS_DENOMINATOR).contains(&coverage_bps) {
+        return Err(Error::InvalidCoverage);
+    }
+    Ok(())
+}
+
+#[contract]
+pub struct LumenInsurance;
+
+#[contractimpl]
+impl LumenInsurance {
+    pub fn initialize(e: Env, admin: Address, usdc: Address, pool: Address, coverage_bps: i128) -> Result<(), Error> {
+        if e.storage().instance().has(&DataKey::Admin) {
+            return Err(Error::AlreadyInitialized);
+        }
+        check_coverage(coverage_bps)?;
+        e.storage().instance().set(&DataKey::Admin, &admin);
+        e.storage().instance().set(&DataKey::Usdc, &usdc);
+        e.storage().instance().set(&DataKey::Pool, &pool);
+        put_default_coverage(&e, coverage_bps);
+        Ok(())
+    }
+
+    // Add capital to the reserve
+    pub fn fund(e: Env, from: Address, amount: i128) -> Result<(), Error> {
+        check_initialized(&e)?;
+        from.require_auth();
+        check_amount(amount)?;
+
+        let usdc_client = token::Client::new(&e, &get_usdc(&e));
+        usdc_client.transfer(&from, &e.current_contract_address(), &amount);
+        put_reserve(&e, get_reserve(&e) + amount);
+
+        e.events().publish((Symbol::new(&e, "reserve_funded"), from), amount);
+        Ok(())
+    }
+
+    // Called by the pool once it has transferred a loan's insurance cut
+    pub fn receive_premium(e: Env, loan_id: u64, amount: i128) -> Result<(), Error> {
+        check_initialized(&e)?;
+        get_pool(&e).require_auth();
+        check_amount(amount)?;
+
+        put_reserve(&e, get_reserve(&e) + amount);
+
+        e.events().publish((Symbol::new(&e, "premium_received"), loan_id), amount);
+        Ok(())
+    }
+
+    // Called by the pool when it writes off a defaulted loan. Pays the covered
+    // share of `loss`, rounded down and capped at the reserve, once per loan.
+    pub fn claim(e: Env, loan_id: u64, loss: i128) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        let pool = get_pool(&e);
+        pool.require_auth();
+        check_amount(loss)?;
+        if is_claimed(&e, loan_id) {
+            return Err(Error::AlreadyClaimed);
+        }
+
+        let reserve = get_reserve(&e);
+        let paid = (loss * get_coverage(&e, loan_id) / BPS_DENOMINATOR).min(reserve);
+        if paid > 0 {
+            let usdc_client = token::Client::new(&e, &get_usdc(&e));
+            usdc_client.transfer(&e.current_contract_address(), &pool, &paid);
+        }
+        put_reserve(&e, reserve - paid);
+        put_claimed(&e, loan_id, paid);
+
+        e.events().publish((Symbol::new(&e, "claim_paid"), loan_id), (loss, paid));
+        Ok(paid)
+    }
+
+    pub fn set_default_coverage(e: Env, coverage_bps: i128) -> Result<(), Error> {
+        check_initialized(&e)?;
+        get_admin(&e).require_auth();
+        check_coverage(coverage_bps)?;
+        put_default_coverage(&e, coverage_bps);
+        Ok(())
+    }
+
+    pub fn set_coverage(e: Env, loan_id: u64, coverage_bps: i128) -> Result<(), Error> {
+        check_initialized(&e)?;
+        get_admin(&e).require_auth();
+        check_coverage(coverage_bps)?;
+        put_coverage(&e, loan_id, coverage_bps);
+        Ok(())
+    }
+
+    pub fn get_coverage(e: Env, loan_id: u64) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_coverage(&e, loan_id))
+    }
+
+    pub fn get_reserve(e: Env) -> Result<i128, Error> {
+        check_initialized(&e)?;
+        Ok(get_reserve(&e))
+    }
+
+    pub fn get_pool(e: Env) -> Result<Address, Error> {
+        check_initialized(&e)?;
+        Ok(get_pool(&e))
+    }
+
+    pub fn get_usdc_address(e: Env) -> Result<Address, Error> {
+        check_initialized(&e)?;
+        Ok(get_usdc(&e))
+    }
+}
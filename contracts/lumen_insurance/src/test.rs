@@ -0,0 +1,65 @@
+#![cfg(test)]
+extern crate std;
+
+use crate::{Error, LumenInsurance, LumenInsuranceClient};
+
+use soroban_sdk::{testutils::Address as _, token, Address, Env};
+
+fn create_token_contract<'a>(e: &Env, admin: &Address) -> (token::Client<'a>, token::StellarAssetClient<'a>) {
+    let address = e.register_stellar_asset_contract(admin.clone());
+    (token::Client::new(e, &address), token::StellarAssetClient::new(e, &address))
+}
+
+fn create_insurance_contract<'a>(
+    e: &Env,
+    admin: &Address,
+    usdc: &Address,
+    pool: &Address,
+    coverage_bps: i128,
+) -> LumenInsuranceClient<'a> {
+    let insurance = LumenInsuranceClient::new(e, &e.register_contract(None, LumenInsurance));
+    insurance.initialize(admin, usdc, pool, &coverage_bps);
+    insurance
+}
+
+#[test]
+fn test_premiums_and_claims() {
+    let e = Env::default();
+    e.mock_all_auths();
+
+    let admin = Address::generate(&e);
+    let pool = Address::generate(&e);
+    let funder = Address::generate(&e);
+
+    let (usdc, usdc_admin) = create_token_contract(&e, &admin);
+    let insurance = create_insurance_contract(&e, &admin, &usdc.address, &pool, 5_000);
+    assert_eq!(
+        insurance.try_initialize(&admin, &usdc.address, &pool, &5_000),
+        Err(Ok(Error::AlreadyInitialized))
+    );
+
+    usdc_admin.mint(&funder, &1_000);
+    insurance.fund(&funder, &1_000);
+
+    // The pool sends its cut first, then reports it
+    usdc_admin.mint(&pool, &100);
+    usdc.transfer(&pool, &insurance.address, &100);
+    insurance.receive_premium(&1, &100);
+    assert_eq!(insurance.get_reserve(), 1_100);
+
+    // Default coverage unless set for the loan
+    assert_eq!(insurance.try_set_coverage(&2, &10_001), Err(Ok(Error::InvalidCoverage)));
+    insurance.set_coverage(&2, &8_000);
+    assert_eq!(insurance.get_coverage(&1), 5_000);
+    assert_eq!(insurance.get_coverage(&2), 8_000);
+
+    assert_eq!(insurance.claim(&1, &501), 250);
+    assert_eq!(insurance.try_claim(&1, &501), Err(Ok(Error::AlreadyClaimed)));
+    assert_eq!(usdc.balance(&pool), 250);
+
+    // Never pays out more than the reserve holds
+    assert_eq!(insurance.claim(&2, &10_000), 850);
+    assert_eq!(insurance.get_reserve(), 0);
+    assert_eq!(usdc.balance(&insurance.address), 0);
+    assert_eq!(usdc.balance(&pool), 1_100);
+}